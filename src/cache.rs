@@ -0,0 +1,442 @@
+//! The scan cache records the filtered listing of every directory, keyed on
+//! the directory's own mtime and ctime. Creating, deleting or renaming an
+//! entry changes both, so while they are the same the listing is still valid
+//! and the directory doesn't need to be read again or matched against ignore
+//! files. Only the entries in it need to be stat'd, because modifying a file
+//! doesn't change its directory's times.
+//!
+//! The format is text, one record per line:
+//!
+//! ```text
+//! maxtime-cache 1
+//! key <hex>
+//! dir <mtime nanos> <ctime nanos> <ignore files hex> <path>
+//! file <name>
+//! subdir <name>
+//! end <number of dirs>
+//! cache-dir <names hex> <path>
+//! ```
+//!
+//! The key covers everything that affects the listings, e.g. the paths and
+//! filter options. Paths are escaped so they never contain spaces, newlines or
+//! `%` (see `escape()`). Anything unexpected makes the whole cache invalid.
+//!
+//! The cache file is often in one of the scanned directories, and writing it
+//! changes that directory's ctime, so its listing would never be used. The
+//! optional `cache-dir` line names that directory and has a digest of all
+//! the names in it once the cache was written. If it still has exactly those
+//! names then nothing was created, deleted or renamed in it since it was
+//! listed, whatever its times.
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fmt::Write as _;
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, Result};
+use sha2::{Digest, Sha256};
+
+use crate::hash::{self, SetDigest};
+use crate::TimeKind;
+
+const HEADER: &str = "maxtime-cache 1";
+
+/// Directories modified this close to the start of the scan aren't cached,
+/// because they could be modified again after they were read without their
+/// times changing, if the filesystem's timestamps are coarse.
+pub(crate) const RACY_WINDOW: Duration = Duration::from_secs(1);
+
+/// What a directory's cached listing depends on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct DirStat {
+    mtime: i128,
+    ctime: i128,
+    // Digest of the ignore files in the directory, because editing one in
+    // place doesn't change the directory's times.
+    ignore_files: String,
+}
+
+impl DirStat {
+    /// Get the stat of a directory given its metadata. Returns `None` if its
+    /// times aren't available, so it can't be cached. That is always the case
+    /// outside Unix, since only the ctime reliably changes when an entry is
+    /// renamed.
+    pub(crate) fn new(dir: &Path, metadata: &Metadata, ignore_file_names: &[String]) -> Option<Self> {
+        Some(Self {
+            mtime: nanos(metadata.modified().ok()?),
+            ctime: nanos(TimeKind::Ctime.get(metadata).ok()?),
+            ignore_files: ignore_files_digest(dir, ignore_file_names),
+        })
+    }
+
+    /// True if the directory was modified too close to `scan_start` to be
+    /// cached.
+    pub(crate) fn is_racy(&self, scan_start: SystemTime, window: Duration) -> bool {
+        self.mtime.max(self.ctime) >= nanos(scan_start) - window.as_nanos() as i128
+    }
+}
+
+/// An entry in a cached directory listing.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Child {
+    pub name: OsString,
+    pub is_dir: bool,
+}
+
+/// A cached directory listing.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct DirRecord {
+    pub stat: DirStat,
+    pub children: Vec<Child>,
+}
+
+/// The listings of all the directories scanned.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct Cache {
+    key: String,
+    dirs: BTreeMap<PathBuf, DirRecord>,
+    // The scanned directory the cache file is in, and the digest of the names
+    // in it after the cache was written.
+    cache_dir: Option<(PathBuf, String)>,
+}
+
+impl Cache {
+    pub(crate) fn new(key: String) -> Self {
+        Self {
+            key,
+            dirs: BTreeMap::new(),
+            cache_dir: None,
+        }
+    }
+
+    /// Read a cache file. Returns `None` if it doesn't exist, is for a
+    /// different key, or is invalid in any way.
+    pub(crate) fn load(path: &Path, key: &str) -> Option<Self> {
+        let contents = std::fs::read_to_string(path).ok()?;
+        let mut cache = Self::parse(&contents)?;
+        if cache.key != key {
+            return None;
+        }
+        // Writing the cache changed its directory's times, so if nothing else
+        // has changed the listing is still valid with the new ones. They are
+        // read before the names, so changes made in between aren't missed.
+        if let Some((dir, names)) = cache.cache_dir.take() {
+            if let (Some(record), Ok(metadata)) = (cache.dirs.get_mut(&dir), std::fs::metadata(&dir)) {
+                if let (Some(times), Some((current_names, _))) = (dir_times(&metadata), names_digest(&dir, None)) {
+                    if current_names == names {
+                        (record.stat.mtime, record.stat.ctime) = times;
+                    }
+                }
+            }
+        }
+        Some(cache)
+    }
+
+    fn parse(contents: &str) -> Option<Self> {
+        let mut lines = contents.lines();
+        if lines.next()? != HEADER {
+            return None;
+        }
+        let key = lines.next()?.strip_prefix("key ")?.to_owned();
+        let mut dirs: Vec<(PathBuf, DirRecord)> = Vec::new();
+        for line in lines.by_ref() {
+            let (kind, rest) = line.split_once(' ')?;
+            match kind {
+                "dir" => {
+                    let mut fields = rest.splitn(4, ' ');
+                    let stat = DirStat {
+                        mtime: fields.next()?.parse().ok()?,
+                        ctime: fields.next()?.parse().ok()?,
+                        ignore_files: fields.next()?.to_owned(),
+                    };
+                    let path = PathBuf::from(unescape(fields.next()?)?);
+                    dirs.push((
+                        path,
+                        DirRecord {
+                            stat,
+                            children: Vec::new(),
+                        },
+                    ));
+                }
+                "file" | "subdir" => dirs.last_mut()?.1.children.push(Child {
+                    name: unescape(rest)?,
+                    is_dir: kind == "subdir",
+                }),
+                "end" => {
+                    let count: usize = rest.parse().ok()?;
+                    let cache_dir = match lines.next() {
+                        Some(line) => {
+                            let (names, dir) = line.strip_prefix("cache-dir ")?.split_once(' ')?;
+                            Some((PathBuf::from(unescape(dir)?), names.to_owned()))
+                        }
+                        None => None,
+                    };
+                    let cache = Self {
+                        key,
+                        dirs: dirs.into_iter().collect(),
+                        cache_dir,
+                    };
+                    // Duplicate directories or anything else after the end make it invalid.
+                    return (count == cache.dirs.len() && lines.next().is_none()).then_some(cache);
+                }
+                _ => return None,
+            }
+        }
+        // No end line, so it was truncated.
+        None
+    }
+
+    /// Write the cache file. It is replaced atomically.
+    pub(crate) fn save(&self, path: &Path) -> Result<()> {
+        let cache_dir = self.cache_dir_line(path);
+        let mut contents = format!("{}\nkey {}\n", HEADER, self.key);
+        for (dir, record) in &self.dirs {
+            let dir = escape(dir.as_os_str()).ok_or_else(|| anyhow!("can't cache path {}", dir.display()))?;
+            let stat = &record.stat;
+            writeln!(contents, "dir {} {} {} {}", stat.mtime, stat.ctime, stat.ignore_files, dir).unwrap();
+            for child in &record.children {
+                let name = escape(&child.name)
+                    .ok_or_else(|| anyhow!("can't cache path {}", Path::new(&child.name).display()))?;
+                writeln!(contents, "{} {}", if child.is_dir { "subdir" } else { "file" }, name).unwrap();
+            }
+        }
+        writeln!(contents, "end {}", self.dirs.len()).unwrap();
+        if let Some(line) = cache_dir {
+            writeln!(contents, "{}", line).unwrap();
+        }
+        crate::stamp::write_atomic(path, contents.as_bytes(), None, "cache file")
+    }
+
+    // The `cache-dir` line for a cache file about to be written to `path`, if
+    // its directory is cached. That is the case if the directory's times are
+    // still the ones in its record, so it hasn't changed since it was listed.
+    // The names are read first so that changes made in between are noticed.
+    // The cache file must already exist, otherwise it's missing from the
+    // listing.
+    fn cache_dir_line(&self, path: &Path) -> Option<String> {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let (names, found) = names_digest(dir, Some(path.file_name()?))?;
+        if !found {
+            return None;
+        }
+        let times = dir_times(&std::fs::metadata(dir).ok()?)?;
+        let (cached_dir, _) = self
+            .dirs
+            .iter()
+            .find(|(_, record)| (record.stat.mtime, record.stat.ctime) == times)?;
+        Some(format!("cache-dir {} {}", names, escape(cached_dir.as_os_str())?))
+    }
+
+    /// The listing for a directory if it's cached and still valid.
+    pub(crate) fn get(&self, dir: &Path, stat: &DirStat) -> Option<&DirRecord> {
+        self.dirs.get(dir).filter(|record| record.stat == *stat)
+    }
+
+    pub(crate) fn insert(&mut self, dir: PathBuf, record: DirRecord) {
+        self.dirs.insert(dir, record);
+    }
+
+    pub(crate) fn remove(&mut self, dir: &Path) -> Option<DirRecord> {
+        self.dirs.remove(dir)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.dirs.is_empty()
+    }
+}
+
+// A directory's mtime and ctime, if they are available.
+fn dir_times(metadata: &Metadata) -> Option<(i128, i128)> {
+    Some((nanos(metadata.modified().ok()?), nanos(TimeKind::Ctime.get(metadata).ok()?)))
+}
+
+// Digest of all the names in a directory, with whether `name` is one of them.
+fn names_digest(dir: &Path, name: Option<&std::ffi::OsStr>) -> Option<(String, bool)> {
+    let mut names = std::fs::read_dir(dir)
+        .ok()?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<std::io::Result<Vec<_>>>()
+        .ok()?;
+    names.sort();
+    let mut hasher = Sha256::new();
+    for name in &names {
+        hasher.update(name.to_string_lossy().as_bytes());
+        hasher.update([0]);
+    }
+    let found = name.is_some_and(|name| names.iter().any(|other| other == name));
+    Some((hash::hex(&hasher.finalize()), found))
+}
+
+/// Digest of the names, times and sizes of the ignore files in a directory,
+/// including the custom ones. Whether it contains `.git` matters too, but
+/// not its times because they change all the time.
+pub(crate) fn ignore_files_digest(dir: &Path, ignore_file_names: &[String]) -> String {
+    let mut digest = SetDigest::default();
+    let standard = [".ignore", ".gitignore", ".git/info/exclude"];
+    for name in standard.iter().copied().chain(ignore_file_names.iter().map(String::as_str)) {
+        if let Some(hash) = file_hash(name.as_ref(), &dir.join(name)) {
+            digest.add(hash);
+        }
+    }
+    if dir.join(".git").exists() {
+        let mut hasher = Sha256::new();
+        hasher.update(b".git");
+        digest.add(hasher.finalize().into());
+    }
+    digest.to_hex()
+}
+
+/// Digest of the paths, times and sizes of some files.
+pub(crate) fn files_digest(paths: &[PathBuf]) -> String {
+    let mut digest = SetDigest::default();
+    for path in paths {
+        if let Some(hash) = file_hash(path.as_os_str(), path) {
+            digest.add(hash);
+        }
+    }
+    digest.to_hex()
+}
+
+// Hash of a file's name, size and times, or `None` if it doesn't exist.
+fn file_hash(name: &std::ffi::OsStr, path: &Path) -> Option<[u8; 32]> {
+    let metadata = std::fs::metadata(path).ok()?;
+    let mut hasher = Sha256::new();
+    hasher.update(name.to_string_lossy().as_bytes());
+    hasher.update([0]);
+    hasher.update(metadata.len().to_le_bytes());
+    hasher.update(metadata.modified().map_or(0, nanos).to_le_bytes());
+    hasher.update(TimeKind::Ctime.get(&metadata).map_or(0, nanos).to_le_bytes());
+    Some(hasher.finalize().into())
+}
+
+fn nanos(time: SystemTime) -> i128 {
+    time::OffsetDateTime::from(time).unix_timestamp_nanos()
+}
+
+/// Escape a path so it has no spaces, control characters or non-ASCII
+/// characters, by encoding those bytes and `%` as `%XX`. Returns `None` for
+/// paths that can't be represented as bytes on this platform.
+pub(crate) fn escape(path: &std::ffi::OsStr) -> Option<String> {
+    let mut out = String::new();
+    for &byte in os_str_bytes(path)? {
+        if byte <= b' ' || byte >= 0x7f || byte == b'%' {
+            write!(out, "%{:02X}", byte).unwrap();
+        } else {
+            out.push(byte as char);
+        }
+    }
+    Some(out)
+}
+
+/// Reverse `escape()`.
+pub(crate) fn unescape(s: &str) -> Option<OsString> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut input = s.bytes();
+    while let Some(byte) = input.next() {
+        if byte == b'%' {
+            let hex = [input.next()?, input.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(byte);
+        }
+    }
+    os_string_from_bytes(bytes)
+}
+
+#[cfg(unix)]
+fn os_str_bytes(s: &std::ffi::OsStr) -> Option<&[u8]> {
+    use std::os::unix::ffi::OsStrExt;
+    Some(s.as_bytes())
+}
+
+#[cfg(not(unix))]
+fn os_str_bytes(s: &std::ffi::OsStr) -> Option<&[u8]> {
+    s.to_str().map(str::as_bytes)
+}
+
+#[cfg(unix)]
+pub(crate) fn os_string_from_bytes(bytes: Vec<u8>) -> Option<OsString> {
+    use std::os::unix::ffi::OsStringExt;
+    Some(OsString::from_vec(bytes))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn os_string_from_bytes(bytes: Vec<u8>) -> Option<OsString> {
+    String::from_utf8(bytes).ok().map(OsString::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape() {
+        for name in ["plain", "with space", "100%", "new\nline", "ünïcode", ""] {
+            let escaped = escape(name.as_ref()).unwrap();
+            assert!(!escaped.contains([' ', '\n']));
+            assert_eq!(unescape(&escaped).unwrap(), name);
+        }
+        assert_eq!(escape("a b%".as_ref()).unwrap(), "a%20b%25");
+        assert!(unescape("%2").is_none());
+        assert!(unescape("%zz").is_none());
+    }
+
+    #[test]
+    fn test_parse() {
+        let mut cache = Cache::new("k".to_owned());
+        cache.insert(
+            PathBuf::from("root dir"),
+            DirRecord {
+                stat: DirStat {
+                    mtime: 1,
+                    ctime: -2,
+                    ignore_files: "00".to_owned(),
+                },
+                children: vec![
+                    Child {
+                        name: "a file".into(),
+                        is_dir: false,
+                    },
+                    Child {
+                        name: "sub".into(),
+                        is_dir: true,
+                    },
+                ],
+            },
+        );
+        let contents = "maxtime-cache 1\nkey k\ndir 1 -2 00 root%20dir\nfile a%20file\nsubdir sub\nend 1\n";
+        assert_eq!(Cache::parse(contents), Some(cache));
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("cache");
+        Cache::parse(contents).unwrap().save(&path).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), contents);
+        assert!(Cache::load(&path, "k").is_some());
+        assert!(Cache::load(&path, "other key").is_none());
+
+        let with_cache_dir = format!("{contents}cache-dir 00 root%20dir\n");
+        assert_eq!(
+            Cache::parse(&with_cache_dir).unwrap().cache_dir,
+            Some((PathBuf::from("root dir"), "00".to_owned()))
+        );
+
+        // Truncated, or with anything unexpected.
+        for invalid in [
+            "maxtime-cache 1\nkey k\ndir 1 -2 00 root%20dir\nfile a%20file\n",
+            "maxtime-cache 2\nkey k\nend 0\n",
+            "maxtime-cache 1\nkey k\nfile a\nend 0\n",
+            "maxtime-cache 1\nkey k\ndir x 2 00 a\nend 1\n",
+            "maxtime-cache 1\nkey k\ndir 1 2 00 a\ndir 1 2 00 a\nend 2\n",
+            "maxtime-cache 1\nkey k\nend 0\nextra\n",
+            "maxtime-cache 1\nkey k\nend 0\ncache-dir 00 a\nextra\n",
+            "maxtime-cache 1\nkey k\nwhat 0\nend 0\n",
+        ] {
+            assert_eq!(Cache::parse(invalid), None, "{:?}", invalid);
+        }
+    }
+}
@@ -0,0 +1,348 @@
+//! Commit times from git, so files can be given the time of the last commit
+//! that changed them instead of the time they were checked out, either by
+//! `restore_mtimes()` or by scanning with `TimeKind::GitCommit`. This runs
+//! `git` against the local repository; it never needs the network.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::cache::os_string_from_bytes;
+use crate::{EntryFilter, ScanResult, Scanner, TimeKind};
+
+/// The time of the last commit that changed each tracked file, for the
+/// repositories containing the scanned paths. Files with uncommitted changes
+/// are left out.
+pub(crate) struct CommitTimes {
+    // For each scanned path, the index of its repository in `repos` and the
+    // path relative to the top of that repository.
+    roots: Vec<(usize, PathBuf)>,
+    // Times by path relative to the top of the repository.
+    repos: Vec<HashMap<PathBuf, SystemTime>>,
+}
+
+impl CommitTimes {
+    /// Read the history of the repositories containing `paths`. Fails if any
+    /// of them isn't in a git repository.
+    pub(crate) fn load(paths: &[PathBuf]) -> Result<Self> {
+        let mut tops: Vec<PathBuf> = Vec::new();
+        let mut repos = Vec::new();
+        let mut roots = Vec::new();
+        for path in paths {
+            let (top, prefix) = locate(path)?;
+            let repo = match tops.iter().position(|existing| *existing == top) {
+                Some(repo) => repo,
+                None => {
+                    repos.push(repo_times(&top)?);
+                    tops.push(top);
+                    tops.len() - 1
+                }
+            };
+            roots.push((repo, prefix));
+        }
+        Ok(Self { roots, repos })
+    }
+
+    /// Commit time of the file at `relative_path` under the scanned path with
+    /// index `root`, or `None` if it is untracked or has uncommitted changes.
+    pub(crate) fn get(&self, root: usize, relative_path: &Path) -> Option<SystemTime> {
+        let (repo, prefix) = &self.roots[root];
+        self.repos[*repo].get(&prefix.join(relative_path)).copied()
+    }
+}
+
+// Find the top of the repository containing `path`, and `path` relative to it.
+fn locate(path: &Path) -> Result<(PathBuf, PathBuf)> {
+    let is_dir = path.is_dir();
+    let dir = match path.parent() {
+        _ if is_dir => path,
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let output = git(dir, &["rev-parse", "--show-toplevel", "--show-prefix"])
+        .with_context(|| anyhow!("error finding the git repository for {}", path.display()))?;
+    let mut lines = output.split(|&byte| byte == b'\n');
+    let (Some(top), Some(prefix)) = (lines.next(), lines.next()) else {
+        bail!("unexpected output from git rev-parse");
+    };
+    let top = path_from_bytes(top)?;
+    let mut prefix = path_from_bytes(prefix)?;
+    if !is_dir {
+        prefix.extend(path.file_name());
+    }
+    Ok((top, prefix))
+}
+
+// Commit times of the tracked files without uncommitted changes in the
+// repository at `top`.
+fn repo_times(top: &Path) -> Result<HashMap<PathBuf, SystemTime>> {
+    let mut pending: HashSet<Vec<u8>> = git(top, &["ls-files", "-z"])?
+        .split(|&byte| byte == 0)
+        .filter(|path| !path.is_empty())
+        .map(<[u8]>::to_vec)
+        .collect();
+    // Records are "XY path". `git status` refreshes the index first, so files
+    // whose mtimes were changed but not their contents aren't included.
+    let status = git(top, &["status", "--porcelain", "-z", "--untracked-files=no", "--no-renames"])?;
+    for record in status.split(|&byte| byte == 0) {
+        if let Some(path) = record.get(3..) {
+            pending.remove(path);
+        }
+    }
+
+    let mut times = HashMap::new();
+    if pending.is_empty() {
+        return Ok(times);
+    }
+
+    // Go back through the history, newest first, until every file has been
+    // seen. Each commit is output as "\0<time>\0\n<path>\0<path>\0...".
+    let mut child = Command::new("git")
+        .arg("-C")
+        .arg(top)
+        .args(["log", "-z", "--format=%x00%ct", "--name-only", "--no-renames"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("error running git")?;
+    let mut reader = BufReader::new(child.stdout.take().unwrap());
+    let mut token = Vec::new();
+    let mut time = None;
+    let mut expect_time = false;
+    let mut first_path = false;
+    while !pending.is_empty() {
+        token.clear();
+        if reader.read_until(0, &mut token).context("error reading git log")? == 0 {
+            break;
+        }
+        if token.last() == Some(&0) {
+            token.pop();
+        }
+        if expect_time {
+            let secs = std::str::from_utf8(&token)
+                .ok()
+                .and_then(|secs| secs.parse().ok())
+                .ok_or_else(|| anyhow!("unexpected output from git log"))?;
+            time = Some(UNIX_EPOCH + Duration::from_secs(secs));
+            expect_time = false;
+            first_path = true;
+        } else if token.is_empty() {
+            expect_time = true;
+        } else {
+            let path = match token.strip_prefix(b"\n") {
+                Some(path) if first_path => path,
+                _ => &token,
+            };
+            first_path = false;
+            if let Some(time) = time.filter(|_| pending.remove(path)) {
+                times.insert(path_from_bytes(path)?, time);
+            }
+        }
+    }
+
+    drop(reader);
+    let finished_early = pending.is_empty();
+    if finished_early {
+        // It may already have exited.
+        let _ = child.kill();
+    }
+    let output = child.wait_with_output().context("error running git")?;
+    if !finished_early && !output.status.success() {
+        bail!("git log failed: {}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(times)
+}
+
+// Run git in `dir` and return its output.
+fn git(dir: &Path, args: &[&str]) -> Result<Vec<u8>> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .context("error running git")?;
+    if !output.status.success() {
+        bail!("git {} failed: {}", args[0], String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(output.stdout)
+}
+
+fn path_from_bytes(bytes: &[u8]) -> Result<PathBuf> {
+    os_string_from_bytes(bytes.to_vec())
+        .map(PathBuf::from)
+        .ok_or_else(|| anyhow!("invalid path in git output: {}", String::from_utf8_lossy(bytes)))
+}
+
+/// What `restore_mtimes()` did.
+#[derive(Debug)]
+pub struct Restored {
+    /// Number of files whose mtimes were set.
+    pub restored: u64,
+    /// Number of tracked files that already had the right mtime.
+    pub unchanged: u64,
+    /// Number of files that were left alone because they are untracked, have
+    /// uncommitted changes, or aren't regular files.
+    pub skipped: u64,
+    /// The scan that found the files. Its errors haven't been checked.
+    pub scan: ScanResult,
+}
+
+/// Set the mtime of every file found by `scanner` to the time of the last
+/// commit that changed it, if it is tracked by git and has no uncommitted
+/// changes. Ignored files aren't visited, as usual.
+pub fn restore_mtimes(mut scanner: Scanner) -> Result<Restored> {
+    let commit_times = CommitTimes::load(scanner.paths())?;
+    let scan = scanner
+        .time_kind(TimeKind::Mtime)
+        .entry_filter(EntryFilter::Files)
+        .collect_entries(true)
+        .scan()?;
+
+    let mut restored = 0;
+    let mut unchanged = 0;
+    let mut skipped = 0;
+    for entry in &scan.entry_list {
+        let relative_path = entry.path.strip_prefix(&scanner.paths()[entry.root]).unwrap_or(&entry.path);
+        match commit_times.get(entry.root, relative_path) {
+            Some(time) if entry.file_type.is_file() && time == entry.time => unchanged += 1,
+            Some(time) if entry.file_type.is_file() => {
+                filetime::set_file_mtime(&entry.path, filetime::FileTime::from_system_time(time))
+                    .with_context(|| anyhow!("error setting mtime of {}", entry.path.display()))?;
+                restored += 1;
+            }
+            _ => skipped += 1,
+        }
+    }
+    Ok(Restored {
+        restored,
+        unchanged,
+        skipped,
+        scan,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Run git with a fixed identity, committing at `time` seconds. Git only
+    // recognises times after 1973 as Unix times.
+    fn run_git(dir: &Path, time: u64, args: &[&str]) {
+        let date = format!("{} +0000", time);
+        let status = Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(["-c", "user.name=Test", "-c", "user.email=test@example.com", "-c", "commit.gpgsign=false"])
+            .args(args)
+            .env("GIT_AUTHOR_DATE", &date)
+            .env("GIT_COMMITTER_DATE", &date)
+            .stdout(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success(), "git {:?} failed", args);
+    }
+
+    fn mtime(path: &Path) -> u64 {
+        let mtime = std::fs::metadata(path).unwrap().modified().unwrap();
+        mtime.duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    #[test]
+    fn test_restore_mtimes() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+        run_git(root, 0, &["init", "-q"]);
+        std::fs::create_dir(root.join("dir")).unwrap();
+        std::fs::write(root.join("a"), "a").unwrap();
+        std::fs::write(root.join("dir/with space"), "b").unwrap();
+        std::fs::write(root.join("modified"), "m").unwrap();
+        std::fs::write(root.join(".gitignore"), "ignored\n").unwrap();
+        run_git(root, 1_000_000_000, &["add", "-A"]);
+        run_git(root, 1_000_000_000, &["commit", "-qm", "one"]);
+        std::fs::write(root.join("a"), "aa").unwrap();
+        run_git(root, 1_100_000_000, &["commit", "-qam", "two"]);
+        run_git(root, 1_200_000_000, &["commit", "-q", "--allow-empty", "-m", "empty"]);
+        std::fs::write(root.join("modified"), "mm").unwrap();
+        std::fs::write(root.join("untracked"), "u").unwrap();
+        std::fs::write(root.join("ignored"), "i").unwrap();
+        for file in ["a", "dir/with space", "modified", "untracked", "ignored"] {
+            filetime::set_file_mtime(root.join(file), filetime::FileTime::from_unix_time(1_500_000_000, 0)).unwrap();
+        }
+
+        let restored = restore_mtimes(Scanner::new(root)).unwrap();
+        assert_eq!((restored.restored, restored.unchanged, restored.skipped), (2, 0, 2));
+        assert_eq!(mtime(&root.join("a")), 1_100_000_000);
+        assert_eq!(mtime(&root.join("dir/with space")), 1_000_000_000);
+        assert_eq!(mtime(&root.join("modified")), 1_500_000_000);
+        assert_eq!(mtime(&root.join("untracked")), 1_500_000_000);
+        assert_eq!(mtime(&root.join("ignored")), 1_500_000_000);
+
+        // Nothing left to do.
+        let restored = restore_mtimes(Scanner::new(root)).unwrap();
+        assert_eq!((restored.restored, restored.unchanged, restored.skipped), (0, 2, 2));
+
+        // Subdirectories and single files of the repository.
+        filetime::set_file_mtime(root.join("a"), filetime::FileTime::from_unix_time(1_500_000_000, 0)).unwrap();
+        filetime::set_file_mtime(root.join("dir/with space"), filetime::FileTime::from_unix_time(1_500_000_000, 0)).unwrap();
+        let mut scanner = Scanner::new(root.join("dir"));
+        scanner.add(root.join("a"));
+        let restored = restore_mtimes(scanner).unwrap();
+        assert_eq!((restored.restored, restored.unchanged, restored.skipped), (2, 0, 0));
+        assert_eq!(mtime(&root.join("a")), 1_100_000_000);
+        assert_eq!(mtime(&root.join("dir/with space")), 1_000_000_000);
+    }
+
+    #[test]
+    fn test_git_commit_time() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+        let set_mtime = |file: &str, secs| {
+            filetime::set_file_mtime(root.join(file), filetime::FileTime::from_unix_time(secs, 0)).unwrap();
+        };
+        run_git(root, 0, &["init", "-q"]);
+        std::fs::write(root.join("a"), "a").unwrap();
+        run_git(root, 1_000_000_000, &["add", "a"]);
+        run_git(root, 1_000_000_000, &["commit", "-qm", "a"]);
+        std::fs::write(root.join("b"), "b").unwrap();
+        run_git(root, 1_100_000_000, &["add", "b"]);
+        run_git(root, 1_100_000_000, &["commit", "-qm", "b"]);
+        run_git(root, 1_200_000_000, &["commit", "-q", "--allow-empty", "-m", "empty"]);
+        // As if freshly cloned.
+        set_mtime("a", 1_500_000_000);
+        set_mtime("b", 1_500_000_000);
+
+        let scan = || {
+            let result = Scanner::new(root).time_kind(TimeKind::GitCommit).scan().unwrap().into_result().unwrap();
+            let max_time = result.max_time.duration_since(UNIX_EPOCH).unwrap().as_secs();
+            let max_path = result.max_path.unwrap();
+            (max_time, max_path.strip_prefix(root).unwrap().to_str().unwrap().to_owned())
+        };
+        assert_eq!(scan(), (1_100_000_000, "b".to_owned()));
+
+        // Untracked files use their mtimes.
+        std::fs::write(root.join("untracked"), "u").unwrap();
+        set_mtime("untracked", 1_050_000_000);
+        assert_eq!(scan(), (1_100_000_000, "b".to_owned()));
+        set_mtime("untracked", 1_300_000_000);
+        assert_eq!(scan(), (1_300_000_000, "untracked".to_owned()));
+        std::fs::remove_file(root.join("untracked")).unwrap();
+
+        // So do modified ones.
+        std::fs::write(root.join("a"), "modified").unwrap();
+        set_mtime("a", 1_400_000_000);
+        assert_eq!(scan(), (1_400_000_000, "a".to_owned()));
+    }
+
+    #[test]
+    fn test_not_a_repository() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        // The temporary directory could be inside a repository, so use a
+        // fake one to stop git looking further up.
+        std::fs::write(temp_dir.path().join(".git"), "gitdir: nowhere\n").unwrap();
+        assert!(restore_mtimes(Scanner::new(temp_dir.path())).is_err());
+    }
+}
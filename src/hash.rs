@@ -0,0 +1,73 @@
+//! Helpers for the SHA-256 hashes used for fingerprints and content hashes:
+//! hex output, and an order-independent digest of a set of hashes so threads
+//! can combine their results in any order.
+
+/// Lowercase hex digits for some bytes, e.g. a SHA-256 hash.
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// A digest of a set of hashes that doesn't depend on the order they were
+/// added in. It's the sum of the first 128 bits of each hash, which unlike
+/// XOR doesn't cancel out if the same hash is added twice.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct SetDigest(u128);
+
+impl SetDigest {
+    pub(crate) fn add(&mut self, hash: [u8; 32]) {
+        self.0 = self.0.wrapping_add(u128::from_be_bytes(hash[..16].try_into().unwrap()));
+    }
+
+    pub(crate) fn merge(&mut self, other: SetDigest) {
+        self.0 = self.0.wrapping_add(other.0);
+    }
+
+    /// 32 lowercase hex digits.
+    pub(crate) fn to_hex(self) -> String {
+        format!("{:032x}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sha2::{Digest, Sha256};
+
+    use super::*;
+
+    fn sha256(data: &[u8]) -> [u8; 32] {
+        Sha256::digest(data).into()
+    }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(hex(&[]), "");
+        assert_eq!(hex(&[0x00, 0x0f, 0xa5, 0xff]), "000fa5ff");
+        assert_eq!(
+            hex(&sha256(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_set_digest() {
+        let a = sha256(b"a");
+        let b = sha256(b"b");
+
+        let mut ab = SetDigest::default();
+        ab.add(a);
+        ab.add(b);
+        let mut ba = SetDigest::default();
+        ba.add(b);
+        let mut just_a = SetDigest::default();
+        just_a.add(a);
+        ba.merge(just_a);
+        assert_eq!(ab, ba);
+
+        // Adding the same hash twice doesn't cancel out.
+        let mut aa = SetDigest::default();
+        aa.add(a);
+        aa.add(a);
+        assert_ne!(aa, SetDigest::default());
+        assert_eq!(aa.to_hex().len(), 32);
+    }
+}
@@ -0,0 +1,88 @@
+//! Just enough JSON writing for maxtime's output, which is all strings,
+//! integers, arrays and objects.
+
+use std::fmt::Write;
+use std::path::Path;
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+
+/// A JSON string literal.
+pub(crate) fn string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// An optional string as a JSON string or `null`.
+pub(crate) fn opt_string(s: Option<&str>) -> String {
+    s.map_or_else(|| "null".to_owned(), string)
+}
+
+/// A path as a JSON string. Non-UTF-8 paths are converted lossily.
+pub(crate) fn path(path: &Path) -> String {
+    string(&path.to_string_lossy())
+}
+
+/// An optional path as a JSON string or `null`.
+pub(crate) fn opt_path(path: Option<&Path>) -> String {
+    path.map_or_else(|| "null".to_owned(), self::path)
+}
+
+/// A JSON array of already encoded values.
+pub(crate) fn array(values: impl IntoIterator<Item = String>) -> String {
+    format!("[{}]", values.into_iter().collect::<Vec<_>>().join(","))
+}
+
+/// A JSON object of already encoded values.
+pub(crate) fn object<'a>(fields: impl IntoIterator<Item = (&'a str, String)>) -> String {
+    let fields: Vec<_> = fields
+        .into_iter()
+        .map(|(name, value)| format!("{}:{}", string(name), value))
+        .collect();
+    format!("{{{}}}", fields.join(","))
+}
+
+/// A time as nanoseconds since the Unix epoch.
+pub(crate) fn nanos(time: SystemTime) -> String {
+    time::OffsetDateTime::from(time).unix_timestamp_nanos().to_string()
+}
+
+/// A time as an RFC 3339 string in UTC.
+pub(crate) fn rfc3339(time: SystemTime) -> Result<String> {
+    let formatted = time::OffsetDateTime::from(time)
+        .format(&time::format_description::well_known::Rfc3339)
+        .context("error formatting time as RFC 3339")?;
+    Ok(string(&formatted))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_string() {
+        assert_eq!(string("a\"b\\c\nd\u{1}é"), r#""a\"b\\c\nd\u0001é""#);
+    }
+
+    #[test]
+    fn test_object() {
+        let json = object([
+            ("a", array(["1".to_owned(), string("x")])),
+            ("b", opt_path(None)),
+        ]);
+        assert_eq!(json, r#"{"a":[1,"x"],"b":null}"#);
+    }
+}
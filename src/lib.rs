@@ -0,0 +1,24 @@
+//! Find the maximum mtime of any file within a directory as fast as possible,
+//! but respecting `.gitignore` files.
+//!
+//! ```no_run
+//! let result = maxtime::Scanner::new(".").scan()?.into_result()?;
+//! println!("{:?}", result.max_time);
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+mod cache;
+pub mod git;
+mod hash;
+mod json;
+pub mod manifest;
+mod scan;
+pub mod stamp;
+mod time_format;
+mod time_kind;
+#[cfg(target_os = "linux")]
+pub mod watch;
+
+pub use scan::{Entry, EntryFilter, RootResult, ScanError, ScanResult, Scanner, JSON_VERSION};
+pub use time_format::{TimeFormat, TimeZone};
+pub use time_kind::TimeKind;
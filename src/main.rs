@@ -1,208 +1,1074 @@
+use std::io::Write;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
+use std::process::ExitCode;
+use std::time::SystemTime;
 
 use anyhow::{anyhow, Context, Result};
-use clap::Parser;
-use ignore::WalkBuilder;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use maxtime::manifest::Manifest;
+use maxtime::stamp::Stamp;
+use maxtime::{EntryFilter, ScanResult, Scanner, TimeFormat, TimeKind, TimeZone};
+
+/// Exit code for `check` when the stamp is out of date, and for `diff` when
+/// something changed.
+const EXIT_STALE: u8 = 1;
+/// Exit code for errors.
+const EXIT_ERROR: u8 = 2;
+/// Exit code for `--keep-going` when there were errors, so the result only
+/// covers the entries that could be read.
+const EXIT_PARTIAL: u8 = 3;
 
 #[derive(Parser)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, args_conflicts_with_subcommands = true)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Output stamp file. Its contents and mtime will be equal to the max mtime.
     #[arg(long)]
     stamp: Option<PathBuf>,
 
+    /// Always rewrite the stamp file. By default it is left alone if it
+    /// already has the right contents and mtime.
+    #[arg(long, requires = "stamp")]
+    force_stamp: bool,
+
     #[arg(long)]
     quiet: bool,
 
-    /// Path to scan (defaults to current directory).
-    path: Option<PathBuf>,
+    /// Output format.
+    #[arg(long, value_enum, default_value_t = Format::Text)]
+    format: Format,
+
+    /// How to print times in text output: nanos, millis or secs since the
+    /// Unix epoch, rfc3339, iso8601, relative (e.g. "5 minutes ago"), or a
+    /// custom format description such as "[year]-[month]-[day] [hour]:[minute]".
+    #[arg(long, value_name = "FORMAT", default_value = "nanos")]
+    time_format: TimeFormat,
+
+    /// Print dates and times in UTC (the default).
+    #[arg(long, overrides_with = "local")]
+    utc: bool,
+
+    /// Print dates and times in the local time zone.
+    #[arg(long, overrides_with = "utc")]
+    local: bool,
+
+    /// Also print the path of the entry with the max mtime. It is relative to
+    /// the scanned path if there is only one.
+    #[arg(long)]
+    show_path: bool,
+
+    /// After the overall max mtime, print the max mtime of each scanned path.
+    #[arg(long)]
+    per_root: bool,
+
+    /// Also compute a fingerprint of the path, type, size and mtime of every
+    /// entry, which unlike the max mtime changes when files are deleted. It is
+    /// printed on a second line and stored in the stamp file.
+    #[arg(long)]
+    fingerprint: bool,
+
+    /// Also compute a hash of the contents and paths of every entry, printed
+    /// on a second line. With --stamp the stamp contains the hash instead of
+    /// the max mtime, and its mtime is only updated when the hash changes.
+    /// This reads every file so it is much slower.
+    #[arg(long, conflicts_with = "fingerprint")]
+    hash: bool,
+
+    /// Print the N newest entries and their paths, newest first, instead of
+    /// just the max mtime.
+    #[arg(long, value_name = "N")]
+    top: Option<usize>,
+
+    /// Print the path of every entry newer than TIME instead of the max
+    /// mtime, like `find -newer`. TIME is nanoseconds since the Unix epoch,
+    /// an RFC 3339 time such as 2023-05-13T17:46:40Z, or @FILE for the time
+    /// of a file (e.g. a stamp). Paths are as walked.
+    #[arg(long, value_name = "TIME", conflicts_with_all = ["top", "show_path", "per_root", "format"])]
+    newer_than: Option<String>,
+
+    /// Terminate the paths printed by --newer-than with NUL instead of
+    /// newline, for `xargs -0`.
+    #[arg(short = '0', long, requires = "newer_than")]
+    null: bool,
+
+    #[command(flatten)]
+    scan: ScanArgs,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    /// Nanoseconds since the Unix epoch, plus paths if requested.
+    Text,
+    /// A JSON object with the max time, paths, entry counts and errors. See
+    /// the README for the schema.
+    Json,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Check whether anything is newer than a stamp file. Nothing is written
+    /// except the --cache file, if given. Exits with 0 if the stamp is up to
+    /// date and 1 if it is stale or doesn't exist.
+    Check(CheckArgs),
+    /// Keep running, and print the max mtime (and update the stamp file, if
+    /// given) whenever it changes. Only supported on Linux.
+    Watch(WatchArgs),
+    /// Set the mtime of every tracked file to the time of the last commit that
+    /// changed it, e.g. after a fresh clone in CI. Untracked files and files
+    /// with uncommitted changes are left alone.
+    RestoreGit(RestoreGitArgs),
+    /// Save or restore the mtimes of every file, e.g. to keep mtimes in a CI
+    /// cache when the checkout gives every file a new one.
+    #[command(subcommand)]
+    Manifest(ManifestCommand),
+    /// List the files that were added, removed or modified since a manifest
+    /// was saved. Exits with 0 if nothing changed and 1 otherwise.
+    Diff(DiffArgs),
+}
+
+#[derive(Subcommand)]
+enum ManifestCommand {
+    /// Record the path, size, content hash and mtime of every file.
+    Save(ManifestArgs),
+    /// Set the mtime of every file whose size and content hash still match
+    /// the manifest back to the recorded one. Other files are left alone.
+    Restore(ManifestArgs),
+}
+
+#[derive(Args)]
+struct CheckArgs {
+    /// Stamp file previously written with `--stamp`.
+    #[arg(long)]
+    stamp: PathBuf,
+
+    #[command(flatten)]
+    scan: ScanArgs,
+}
+
+#[derive(Args)]
+struct WatchArgs {
+    /// Output stamp file, updated whenever the max mtime changes.
+    #[arg(long)]
+    stamp: Option<PathBuf>,
+
+    /// How long to wait for changes to stop before rescanning, in
+    /// milliseconds, so that a burst of changes only causes one rescan.
+    #[arg(long, value_name = "MS", default_value_t = 100)]
+    debounce: u64,
+
+    #[command(flatten)]
+    scan: ScanArgs,
 }
 
-use std::time::{SystemTime, UNIX_EPOCH};
+#[derive(Args)]
+struct RestoreGitArgs {
+    #[command(flatten)]
+    scan: ScanArgs,
+}
+
+#[derive(Args)]
+struct ManifestArgs {
+    /// The manifest file.
+    file: PathBuf,
 
-struct MtimeVisitor {
-    // Max mtime for the thread.
-    thread_max_mtime: SystemTime,
-    // Max mtime for all threads.
-    max_mtime: Arc<Mutex<SystemTime>>,
-    // If any thread had an error.
-    error: Arc<Mutex<Result<()>>>,
+    #[command(flatten)]
+    scan: ScanArgs,
 }
 
-impl MtimeVisitor {
-    fn new(max_mtime: Arc<Mutex<SystemTime>>, error: Arc<Mutex<Result<()>>>) -> Self {
-        Self {
-            thread_max_mtime: UNIX_EPOCH,
-            max_mtime,
-            error,
+#[derive(Args)]
+struct DiffArgs {
+    /// Manifest previously written with `manifest save`.
+    old: PathBuf,
+
+    /// Output format.
+    #[arg(long, value_enum, default_value_t = Format::Text)]
+    format: Format,
+
+    #[command(flatten)]
+    scan: ScanArgs,
+}
+
+/// Options shared by everything that scans the tree.
+#[derive(Args)]
+struct ScanArgs {
+    /// Which timestamp to use.
+    #[arg(long = "time", value_enum, default_value_t = TimeArg::Mtime)]
+    time_kind: TimeArg,
+
+    /// Only scan files matching this glob (can be repeated). Directories are
+    /// still descended into. Globs use .gitignore syntax and override ignore
+    /// files, so matching files are scanned even if they are gitignored.
+    #[arg(long, value_name = "GLOB")]
+    include: Vec<String>,
+
+    /// Don't scan files or directories matching this glob (can be repeated).
+    /// This takes precedence over --include and ignore files.
+    #[arg(long, value_name = "GLOB")]
+    exclude: Vec<String>,
+
+    /// Ignore the times of directories. A directory's mtime changes whenever
+    /// something in it is created or deleted, even if that is ignored.
+    #[arg(long, conflicts_with = "dirs_only")]
+    files_only: bool,
+
+    /// Only use the times of directories.
+    #[arg(long)]
+    dirs_only: bool,
+
+    /// Also respect ignore files with this name, e.g. .maxtimeignore or
+    /// .dockerignore (can be repeated). They take precedence over .gitignore
+    /// and other standard ignore files.
+    #[arg(long, value_name = "NAME")]
+    ignore_file_name: Vec<String>,
+
+    /// Also respect this ignore file for the whole scan (can be repeated). It
+    /// has lower precedence than all other ignore files.
+    #[arg(long, value_name = "PATH")]
+    ignore_file: Vec<PathBuf>,
+
+    /// Scan hidden files and directories, e.g. `.env`.
+    #[arg(long)]
+    hidden: bool,
+
+    /// Don't read ignore files in the parent directories of the scanned paths.
+    #[arg(long)]
+    no_ignore_parent: bool,
+
+    /// Don't respect `.ignore` files.
+    #[arg(long)]
+    no_ignore_dot: bool,
+
+    /// Don't respect `.gitignore` files.
+    #[arg(long)]
+    no_ignore_vcs: bool,
+
+    /// Don't respect the global gitignore file (git's `core.excludesFile`).
+    #[arg(long)]
+    no_ignore_global: bool,
+
+    /// Don't respect `.git/info/exclude`.
+    #[arg(long)]
+    no_ignore_exclude: bool,
+
+    /// Respect `.gitignore` files even outside a git repository.
+    #[arg(long)]
+    no_require_git: bool,
+
+    /// Don't descend more than N directories below the scanned paths. 0 only
+    /// scans the paths themselves.
+    #[arg(long, value_name = "N")]
+    max_depth: Option<usize>,
+
+    /// Don't cross filesystem boundaries.
+    #[arg(long)]
+    one_file_system: bool,
+
+    /// Cache directory listings in this file to speed up later scans.
+    /// Directories whose mtime and ctime haven't changed aren't read again,
+    /// but the files in them are still checked. Unix only.
+    #[arg(long, value_name = "PATH")]
+    cache: Option<PathBuf>,
+
+    /// Fail if files are deleted during the scan, instead of skipping them.
+    #[arg(long)]
+    strict: bool,
+
+    /// Don't stop at errors such as unreadable directories. The result covers
+    /// everything that could be read, the errors are summarised on stderr and
+    /// the exit code is 3.
+    #[arg(long)]
+    keep_going: bool,
+
+    /// Directories or files to scan (defaults to current directory).
+    paths: Vec<PathBuf>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum TimeArg {
+    /// Modification time.
+    Mtime,
+    /// Status change time (Unix only). Catches files copied with `cp -p`,
+    /// `tar x` or `rsync -t`, which keep old mtimes.
+    Ctime,
+    /// Access time.
+    Atime,
+    /// Birth time, if the platform and filesystem record it.
+    Btime,
+    /// Time of the last commit that changed each file, so the result is the
+    /// same in every clone. Untracked and modified files use their mtime, and
+    /// directories don't count.
+    GitCommit,
+}
+
+impl From<TimeArg> for TimeKind {
+    fn from(time: TimeArg) -> Self {
+        match time {
+            TimeArg::Mtime => Self::Mtime,
+            TimeArg::Ctime => Self::Ctime,
+            TimeArg::Atime => Self::Atime,
+            TimeArg::Btime => Self::Btime,
+            TimeArg::GitCommit => Self::GitCommit,
         }
     }
 }
 
-impl Drop for MtimeVisitor {
-    fn drop(&mut self) {
-        let mut max_mtime = self.max_mtime.lock().unwrap();
-        *max_mtime = max_mtime.max(self.thread_max_mtime);
+impl ScanArgs {
+    fn scanner(&self) -> Scanner {
+        let mut paths = self.paths.iter();
+        let mut scanner = Scanner::new(paths.next().map_or_else(|| PathBuf::from("."), Clone::clone));
+        for path in paths {
+            scanner.add(path);
+        }
+        for glob in &self.include {
+            scanner.include(glob);
+        }
+        for glob in &self.exclude {
+            scanner.exclude(glob);
+        }
+        for name in &self.ignore_file_name {
+            scanner.ignore_file_name(name);
+        }
+        for path in &self.ignore_file {
+            scanner.ignore_file(path);
+        }
+        if let Some(cache) = &self.cache {
+            scanner.cache(cache);
+        }
+        let entry_filter = if self.files_only {
+            EntryFilter::Files
+        } else if self.dirs_only {
+            EntryFilter::Dirs
+        } else {
+            EntryFilter::All
+        };
+        scanner
+            .time_kind(self.time_kind.into())
+            .entry_filter(entry_filter)
+            .strict(self.strict)
+            .keep_going(self.keep_going)
+            .hidden(!self.hidden)
+            .parents(!self.no_ignore_parent)
+            .ignore(!self.no_ignore_dot)
+            .git_ignore(!self.no_ignore_vcs)
+            .git_global(!self.no_ignore_global)
+            .git_exclude(!self.no_ignore_exclude)
+            .require_git(!self.no_require_git)
+            .max_depth(self.max_depth)
+            .same_file_system(self.one_file_system);
+        scanner
+    }
+
+    /// Fail if there were errors during the scan, unless `--keep-going` was
+    /// given in which case they are reported and the exit code says the result
+    /// is partial.
+    fn check_errors(&self, result: ScanResult) -> Result<(ScanResult, ExitCode)> {
+        if !self.keep_going {
+            return Ok((result.into_result()?, ExitCode::SUCCESS));
+        }
+        if result.errors.is_empty() {
+            return Ok((result, ExitCode::SUCCESS));
+        }
+        eprintln!(
+            "Warning: {} error(s) during the scan; the result only covers what could be read:",
+            result.errors.len()
+        );
+        for error in &result.errors {
+            eprintln!("  {}", error);
+        }
+        Ok((result, ExitCode::from(EXIT_PARTIAL)))
+    }
+
+    /// How to display a walked path: relative to the scanned path if there is
+    /// only one, otherwise as walked so it's clear which one it came from.
+    fn display_path<'a>(&self, path: &'a Path) -> std::path::Display<'a> {
+        match self.paths.as_slice() {
+            [] => relative_path(Path::new("."), path).display(),
+            [root] => relative_path(root, path).display(),
+            _ => path.display(),
+        }
     }
 }
 
-impl MtimeVisitor {
-    fn visit_inner(&mut self, entry: std::result::Result<ignore::DirEntry, ignore::Error>) -> Result<()> {
-        let entry = entry.with_context(|| anyhow!("error reading directory entry"))?;
-        let metadata = entry.metadata().with_context(|| anyhow!("error reading metadata for path {}", entry.path().display()))?;
-        let mtime = metadata.modified().with_context(|| anyhow!("error getting modified time for path {}", entry.path().display()))?;
-        self.thread_max_mtime = self.thread_max_mtime.max(mtime);
-        Ok(())
+impl Cli {
+    // `--utc` and `--local` override each other, so whichever is last wins.
+    fn time_zone(&self) -> TimeZone {
+        match (self.utc, self.local) {
+            (false, true) => TimeZone::Local,
+            _ => TimeZone::Utc,
+        }
+    }
+
+    fn format_time(&self, time: SystemTime) -> Result<String> {
+        self.time_format.format(time, self.time_zone())
     }
 }
 
-impl ignore::ParallelVisitor for MtimeVisitor {
-    fn visit(
-        &mut self,
-        entry: std::result::Result<ignore::DirEntry, ignore::Error>,
-    ) -> ignore::WalkState {
+fn main() -> ExitCode {
+    let cli: Cli = Cli::parse();
+
+    let result = match &cli.command {
+        Some(Command::Check(args)) => check(args),
+        Some(Command::Watch(args)) => watch(args),
+        Some(Command::RestoreGit(args)) => restore_git(args),
+        Some(Command::Manifest(ManifestCommand::Save(args))) => manifest_save(args),
+        Some(Command::Manifest(ManifestCommand::Restore(args))) => manifest_restore(args),
+        Some(Command::Diff(args)) => diff(args),
+        None => scan(&cli),
+    };
 
-        match self.visit_inner(entry) {
-            Ok(()) => ignore::WalkState::Continue,
-            Err(e) => {
-                *self.error.lock().unwrap() = Err(e);
-                ignore::WalkState::Quit
-            }
+    match result {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            ExitCode::from(EXIT_ERROR)
         }
     }
 }
 
-struct MtimeVisitorBuilder {
-    // Max mtime overall.
-    max_mtime: Arc<Mutex<SystemTime>>,
-    // If any thread had an error.
-    error: Arc<Mutex<Result<()>>>,
-}
+fn scan(cli: &Cli) -> Result<ExitCode> {
+    let mut scanner = cli.scan.scanner();
+    if let Some(reference) = &cli.newer_than {
+        let time = reference_time(reference, cli.scan.time_kind.into())?;
+        scanner.collect_entries(true).collect_newer_than(time);
+    }
+    let result = scanner
+        .top(cli.top.unwrap_or(0))
+        .fingerprint(cli.fingerprint)
+        .hash(cli.hash)
+        .scan()?;
+    let (result, exit_code) = cli.scan.check_errors(result)?;
+    let max_mtime = result.max_time;
 
-impl Default for MtimeVisitorBuilder {
-    fn default() -> Self {
-        Self {
-            max_mtime: Arc::new(Mutex::new(UNIX_EPOCH)),
-            error: Arc::new(Mutex::new(Ok(()))),
+    if cli.quiet {
+        // Nothing to print.
+    } else if cli.format == Format::Json {
+        println!("{}", result.to_json()?);
+    } else if cli.newer_than.is_some() {
+        // Print the newer entries.
+        let mut stdout = std::io::stdout().lock();
+        for entry in &result.entry_list {
+            write_path(&mut stdout, &entry.path)?;
+            stdout.write_all(if cli.null { b"\0" } else { b"\n" })?;
+        }
+        stdout.flush()?;
+    } else if cli.top.is_some() {
+        // Print the newest entries.
+        for (entry_path, mtime) in &result.top {
+            println!("{} {}", cli.format_time(*mtime)?, cli.scan.display_path(entry_path));
+        }
+    } else {
+        // Print the maximum mtime.
+        let max_mtime_formatted = cli.format_time(max_mtime)?;
+        match &result.max_path {
+            Some(max_path) if cli.show_path => {
+                println!("{} {}", max_mtime_formatted, cli.scan.display_path(max_path))
+            }
+            _ => println!("{}", max_mtime_formatted),
+        }
+        if let Some(fingerprint) = &result.fingerprint {
+            println!("{}", fingerprint);
+        }
+        if let Some(hash) = &result.hash {
+            println!("{}", hash);
+        }
+        if cli.per_root {
+            for root in &result.roots {
+                println!("{} {}", cli.format_time(root.max_time)?, root.path.display());
+            }
+        }
+    }
+
+    // If requested save it to a file and set that file's mtime to the
+    // maximum mtime.
+    // With --hash it contains the hash instead, and its mtime is set to now
+    // when the hash changes.
+    if let Some(stamp_path) = &cli.stamp {
+        let stamp = match (&result.hash, &result.fingerprint) {
+            (Some(hash), _) => Stamp::with_hash(hash, SystemTime::now()),
+            (None, Some(fingerprint)) => Stamp::with_fingerprint(max_mtime, fingerprint),
+            (None, None) => Stamp::new(max_mtime),
+        };
+        if cli.force_stamp {
+            stamp.write(stamp_path)?;
+        } else if result.hash.is_some() {
+            stamp.update_contents(stamp_path)?;
+        } else {
+            stamp.update(stamp_path)?;
         }
     }
+    Ok(exit_code)
 }
 
-impl<'s> ignore::ParallelVisitorBuilder<'s> for MtimeVisitorBuilder {
-    fn build(&mut self) -> Box<dyn ignore::ParallelVisitor + 's> {
-        Box::new(MtimeVisitor::new(
-            self.max_mtime.clone(),
-            self.error.clone(),
-        ))
+fn check(args: &CheckArgs) -> Result<ExitCode> {
+    let Some(stamp) = Stamp::read(&args.stamp)? else {
+        return Ok(ExitCode::from(EXIT_STALE));
+    };
+    let stamp_time = stamp.time();
+
+    // A content hash stamp only changes when the hash does.
+    if let Some(stamp_hash) = stamp.hash() {
+        let result = args.scan.scanner().hash(true).scan()?;
+        let (result, exit_code) = args.scan.check_errors(result)?;
+        return Ok(if result.hash.as_deref() != Some(stamp_hash) {
+            ExitCode::from(EXIT_STALE)
+        } else {
+            exit_code
+        });
+    }
+
+    // Stop as soon as anything newer is found; we don't need the actual max.
+    // That doesn't work with a fingerprint because it covers every entry.
+    let mut scanner = args.scan.scanner();
+    match stamp.fingerprint() {
+        Some(_) => scanner.fingerprint(true),
+        None => scanner.quit_if_newer_than(stamp_time),
+    };
+    let result = scanner.scan()?;
+    let (result, exit_code) = args.scan.check_errors(result)?;
+
+    if result.max_time > stamp_time || result.fingerprint.as_deref() != stamp.fingerprint() {
+        Ok(ExitCode::from(EXIT_STALE))
+    } else {
+        Ok(exit_code)
     }
 }
 
-fn main() -> Result<()> {
-    let cli: Cli = Cli::parse();
+#[cfg(target_os = "linux")]
+fn watch(args: &WatchArgs) -> Result<ExitCode> {
+    let mut last_max = None;
+    let debounce = std::time::Duration::from_millis(args.debounce);
+    maxtime::watch::watch(args.scan.scanner(), debounce, |result| {
+        let (result, _) = args.scan.check_errors(result)?;
+        if last_max != Some(result.max_time) {
+            last_max = Some(result.max_time);
+            if let Some(stamp_path) = &args.stamp {
+                Stamp::new(result.max_time).update(stamp_path)?;
+            }
+            println!("{}", TimeFormat::Nanos.format(result.max_time, TimeZone::Utc)?);
+        }
+        Ok(std::ops::ControlFlow::Continue(()))
+    })?;
+    Ok(ExitCode::SUCCESS)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn watch(_args: &WatchArgs) -> Result<ExitCode> {
+    anyhow::bail!("watch is only supported on Linux")
+}
 
-    let path = cli.path.as_deref().unwrap_or_else(|| Path::new("."));
+fn restore_git(args: &RestoreGitArgs) -> Result<ExitCode> {
+    let restored = maxtime::git::restore_mtimes(args.scan.scanner())?;
+    let (_, exit_code) = args.scan.check_errors(restored.scan)?;
+    println!(
+        "Restored {} mtime(s), {} already up to date, {} untracked or modified file(s) skipped",
+        restored.restored, restored.unchanged, restored.skipped
+    );
+    Ok(exit_code)
+}
 
-    let mut visitor_builder = MtimeVisitorBuilder::default();
+fn manifest_save(args: &ManifestArgs) -> Result<ExitCode> {
+    let (manifest, scan) = Manifest::scan(args.scan.scanner())?;
+    let (_, exit_code) = args.scan.check_errors(scan)?;
+    manifest.save(&args.file)?;
+    println!("Recorded {} file(s)", manifest.files.len());
+    Ok(exit_code)
+}
 
-    WalkBuilder::new(path)
-        .build_parallel()
-        .visit(&mut visitor_builder);
+fn manifest_restore(args: &ManifestArgs) -> Result<ExitCode> {
+    let manifest = Manifest::load(&args.file)?;
+    let restored = manifest.restore(args.scan.scanner())?;
+    let (_, exit_code) = args.scan.check_errors(restored.scan)?;
+    println!(
+        "Restored {} mtime(s), {} already up to date, {} changed and {} new file(s) skipped",
+        restored.restored, restored.unchanged, restored.changed, restored.added
+    );
+    Ok(exit_code)
+}
 
-    let max_mtime = *visitor_builder.max_mtime.lock().unwrap();
-    let error = std::mem::replace(&mut *visitor_builder.error.lock().unwrap(), Ok(()));
+fn diff(args: &DiffArgs) -> Result<ExitCode> {
+    let old = Manifest::load(&args.old)?;
+    let (new, scan) = Manifest::scan(args.scan.scanner())?;
+    let (_, exit_code) = args.scan.check_errors(scan)?;
+    let diff = old.diff(&new);
 
-    // Exit if any thread had an error.
-    error?;
+    if args.format == Format::Json {
+        println!("{}", diff.to_json()?);
+    } else {
+        for file in &diff.added {
+            println!("added {}", file.path.display());
+        }
+        for file in &diff.removed {
+            println!("removed {}", file.path.display());
+        }
+        for (old, new) in &diff.modified {
+            let changes: Vec<_> = [
+                ("mtime", old.mtime != new.mtime),
+                ("size", old.size != new.size),
+                ("contents", old.hash != new.hash),
+            ]
+            .into_iter()
+            .filter_map(|(name, changed)| changed.then_some(name))
+            .collect();
+            println!("modified {} ({})", new.path.display(), changes.join(", "));
+        }
+    }
 
-    let max_mtime_nanos = time::OffsetDateTime::from(max_mtime).unix_timestamp_nanos();
+    Ok(if diff.is_empty() { exit_code } else { ExitCode::from(EXIT_STALE) })
+}
 
-    if !cli.quiet {
-        // Print the maximum mtime.
-        println!("{}", max_mtime_nanos);
+/// The time given to `--newer-than`: nanoseconds since the Unix epoch, an
+/// RFC 3339 time, or `@FILE` for the given timestamp of a file.
+fn reference_time(reference: &str, time_kind: TimeKind) -> Result<SystemTime> {
+    if let Some(path) = reference.strip_prefix('@') {
+        return std::fs::metadata(path)
+            .and_then(|metadata| time_kind.get(&metadata))
+            .with_context(|| anyhow!("error getting {} of reference file {}", time_kind, path));
     }
+    let time = match reference.parse::<i128>() {
+        Ok(nanos) => time::OffsetDateTime::from_unix_timestamp_nanos(nanos).ok(),
+        Err(_) => time::OffsetDateTime::parse(reference, &time::format_description::well_known::Rfc3339).ok(),
+    };
+    time.map(SystemTime::from).ok_or_else(|| {
+        anyhow!(
+            "invalid time {}: expected nanoseconds since the Unix epoch, an RFC 3339 time or @FILE",
+            reference
+        )
+    })
+}
 
-    // If requested save it to a file and set that file's mtime to the
-    // maximum mtime.
-    if let Some(stamp) = &cli.stamp {
-        std::fs::write(stamp, format!("{}\n", max_mtime_nanos))
-            .with_context(|| anyhow!("error writing stamp file {}", stamp.display()))?;
-        filetime::set_file_mtime(stamp, filetime::FileTime::from_system_time(max_mtime))
-            .with_context(|| anyhow!("error setting mtime of stamp file {}", stamp.display()))?;
+/// Write a path as raw bytes where possible, so that any path can be read
+/// back, e.g. by `xargs -0`.
+fn write_path(out: &mut impl Write, path: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        out.write_all(path.as_os_str().as_bytes())
+    }
+    #[cfg(not(unix))]
+    {
+        write!(out, "{}", path.display())
+    }
+}
+
+/// Strip the scanned path from a walked path. The scanned path itself becomes `.`.
+fn relative_path<'a>(root: &Path, path: &'a Path) -> &'a Path {
+    match path.strip_prefix(root) {
+        Ok(relative) if relative.as_os_str().is_empty() => Path::new("."),
+        Ok(relative) => relative,
+        Err(_) => path,
     }
-    Ok(())
 }
 
 // Test module
 #[cfg(test)]
 mod tests {
-    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
 
-    use rand::distributions::Alphanumeric;
-    use rand::Rng;
+    #[test]
+    fn test_cli() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().join("root");
+        std::fs::create_dir(&root).unwrap();
+        let file = root.join("file");
+        std::fs::write(&file, "").unwrap();
+
+        let mtime = UNIX_EPOCH + Duration::from_secs(100);
+        filetime::set_file_mtime(&file, filetime::FileTime::from_system_time(mtime)).unwrap();
+        filetime::set_file_mtime(&root, filetime::FileTime::from_system_time(UNIX_EPOCH)).unwrap();
+
+        let mut cmd = assert_cmd::Command::cargo_bin("maxtime").unwrap();
 
-    fn rand_string(n: usize) -> String {
-        let mut rng = rand::thread_rng();
-        (0..n).map(|_| rng.sample(Alphanumeric) as char).collect()
+        cmd.arg(&root);
+        cmd.assert().success().stdout("100000000000\n");
+
+        let mut cmd = assert_cmd::Command::cargo_bin("maxtime").unwrap();
+
+        cmd.arg("--show-path").arg(&root);
+        cmd.assert().success().stdout("100000000000 file\n");
     }
 
-    fn set_mtime(path: &Path, max_mtime: &mut SystemTime) {
-        let mut rng = rand::thread_rng();
-        let mtime = UNIX_EPOCH + std::time::Duration::from_secs(rng.gen_range(0..100));
-        filetime::set_file_mtime(path, filetime::FileTime::from_system_time(mtime)).unwrap();
-        *max_mtime = mtime.max(*max_mtime);
+    #[test]
+    fn test_check() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().join("root");
+        std::fs::create_dir(&root).unwrap();
+        let file = root.join("file");
+        std::fs::write(&file, "").unwrap();
+        let stamp = temp_dir.path().join("out.stamp");
+
+        let set_mtime = |path: &std::path::Path, secs| {
+            let mtime = UNIX_EPOCH + Duration::from_secs(secs);
+            filetime::set_file_mtime(path, filetime::FileTime::from_system_time(mtime)).unwrap();
+        };
+        set_mtime(&file, 100);
+        set_mtime(&root, 0);
+
+        let check = || {
+            let mut cmd = assert_cmd::Command::cargo_bin("maxtime").unwrap();
+            cmd.arg("check").arg("--stamp").arg(&stamp).arg(&root);
+            cmd.assert()
+        };
+
+        // No stamp yet.
+        check().code(1).stdout("");
+
+        let mut cmd = assert_cmd::Command::cargo_bin("maxtime").unwrap();
+        cmd.arg("--quiet").arg("--stamp").arg(&stamp).arg(&root);
+        cmd.assert().success();
+
+        check().code(0);
+
+        set_mtime(&file, 200);
+        check().code(1);
+        // The stamp was not updated.
+        assert_eq!(std::fs::read_to_string(&stamp).unwrap(), "100000000000\n");
     }
 
     #[test]
-    fn test_mtime() {
-        fn make_rand_dir(path: &Path, max_levels: usize, max_mtime: &mut SystemTime) {
-            if max_levels == 0 {
-                return;
-            }
-            // Create this directory.
-            let dir = path.join(rand_string(10));
-            std::fs::create_dir(&dir).unwrap();
+    fn test_stamp_in_tree() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().join("root");
+        std::fs::create_dir(&root).unwrap();
+        let file = root.join("file");
+        std::fs::write(&file, "").unwrap();
+        filetime::set_file_mtime(&file, filetime::FileTime::from_unix_time(100, 0)).unwrap();
+        filetime::set_file_mtime(&root, filetime::FileTime::from_unix_time(0, 0)).unwrap();
+        let stamp = root.join("out.stamp");
 
-            let mut rng = rand::thread_rng();
+        // Writing the stamp doesn't change the result, so the second run
+        // leaves it alone.
+        let mut stamps = Vec::new();
+        for _ in 0..2 {
+            let mut cmd = assert_cmd::Command::cargo_bin("maxtime").unwrap();
+            cmd.arg("--stamp").arg(&stamp).arg(&root);
+            cmd.assert().success().stdout("100000000000\n");
+            stamps.push((std::fs::read_to_string(&stamp).unwrap(), std::fs::metadata(&stamp).unwrap()));
+        }
+        assert_eq!(stamps[0].0, "100000000000\n");
+        assert_eq!(stamps[1].0, stamps[0].0);
+        assert_eq!(stamps[1].1.modified().unwrap(), stamps[0].1.modified().unwrap());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            // Not replaced by a new file.
+            assert_eq!(stamps[1].1.ino(), stamps[0].1.ino());
+        }
+    }
 
-            // Create some directories.
-            for _ in 0..rng.gen_range(0..3) {
-                make_rand_dir(&dir, max_levels - 1, max_mtime);
-            }
-            // Create some files in this directory.
-            for _ in 0..rng.gen_range(0..20) {
-                let file_name = dir.join(rand_string(8));
-                std::fs::write(&file_name, rand_string(100)).unwrap();
-                // Set the mtime for this file.
-                set_mtime(&file_name, max_mtime);
-            }
-            // Set the mtime for this directory.
-            set_mtime(&dir, max_mtime);
+    #[test]
+    fn test_check_fingerprint() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().join("root");
+        std::fs::create_dir(&root).unwrap();
+        for name in ["old", "new"] {
+            std::fs::write(root.join(name), "").unwrap();
         }
+        filetime::set_file_mtime(root.join("old"), filetime::FileTime::from_unix_time(1, 0)).unwrap();
+        filetime::set_file_mtime(root.join("new"), filetime::FileTime::from_unix_time(2, 0)).unwrap();
+        let stamp = temp_dir.path().join("out.stamp");
+
+        let mut cmd = assert_cmd::Command::cargo_bin("maxtime").unwrap();
+        cmd.arg("--files-only").arg("--fingerprint").arg("--stamp").arg(&stamp).arg(&root);
+        let assert = cmd.assert().success();
+        let stdout = std::str::from_utf8(&assert.get_output().stdout).unwrap();
+        let fingerprint = stdout.lines().nth(1).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&stamp).unwrap(),
+            format!("2000000000\nfingerprint {}\n", fingerprint)
+        );
+
+        let check = || {
+            let mut cmd = assert_cmd::Command::cargo_bin("maxtime").unwrap();
+            cmd.arg("check").arg("--files-only").arg("--stamp").arg(&stamp).arg(&root);
+            cmd.assert()
+        };
+        check().code(0);
+
+        // Deleting the older file is noticed.
+        std::fs::remove_file(root.join("old")).unwrap();
+        check().code(1);
+    }
+
+    #[test]
+    fn test_hash() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().join("root");
+        std::fs::create_dir(&root).unwrap();
+        std::fs::write(root.join("file"), "contents").unwrap();
+        let stamp = temp_dir.path().join("out.stamp");
+
+        let mut cmd = assert_cmd::Command::cargo_bin("maxtime").unwrap();
+        cmd.arg("--hash").arg("--stamp").arg(&stamp).arg(&root);
+        cmd.assert().success();
+        assert!(std::fs::read_to_string(&stamp).unwrap().starts_with("hash "));
+
+        let check = || {
+            let mut cmd = assert_cmd::Command::cargo_bin("maxtime").unwrap();
+            cmd.arg("check").arg("--stamp").arg(&stamp).arg(&root);
+            cmd.assert()
+        };
+        check().code(0);
+
+        // Like a fresh clone: same contents, new mtimes.
+        std::fs::write(root.join("file"), "contents").unwrap();
+        filetime::set_file_mtime(root.join("file"), filetime::FileTime::from_unix_time(2_000_000_000, 0)).unwrap();
+        check().code(0);
+
+        std::fs::write(root.join("file"), "changed").unwrap();
+        check().code(1);
+    }
 
-        // Create temporary directory.
+    #[test]
+    fn test_cache() {
         let temp_dir = tempfile::tempdir().unwrap();
-        // let temp_dir_path = temp_dir.path();
-        let temp_dir_path = temp_dir.path();
+        let root = temp_dir.path().join("root");
+        std::fs::create_dir(&root).unwrap();
+        let file = root.join("file");
+        std::fs::write(&file, "").unwrap();
+        filetime::set_file_mtime(&file, filetime::FileTime::from_unix_time(100, 0)).unwrap();
+        filetime::set_file_mtime(&root, filetime::FileTime::from_unix_time(0, 0)).unwrap();
+        let cache = temp_dir.path().join("cache");
+
+        for _ in 0..2 {
+            let mut cmd = assert_cmd::Command::cargo_bin("maxtime").unwrap();
+            cmd.arg("--cache").arg(&cache).arg(&root);
+            cmd.assert().success().stdout("100000000000\n");
+        }
+        assert!(std::fs::read_to_string(&cache).unwrap().starts_with("maxtime-cache 1\n"));
+    }
 
-        // Create random directory structure with random mtimes.
-        let root = temp_dir_path.join("root");
+    #[test]
+    fn test_json() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().join("root");
         std::fs::create_dir(&root).unwrap();
+        filetime::set_file_mtime(&root, filetime::FileTime::from_unix_time(1, 0)).unwrap();
 
-        // Max mtime for all files and directories.
-        let mut max_mtime = UNIX_EPOCH;
+        let mut cmd = assert_cmd::Command::cargo_bin("maxtime").unwrap();
 
-        make_rand_dir(&root, 5, &mut max_mtime);
+        cmd.arg("--format").arg("json").arg(&root);
+        let assert = cmd.assert().success();
+        let stdout = std::str::from_utf8(&assert.get_output().stdout).unwrap();
+        assert!(stdout.starts_with(r#"{"version":1,"max_time_nanos":1000000000,"max_time":"1970-01-01T00:00:01Z","#));
+        assert!(stdout.ends_with("}\n"));
+    }
 
-        set_mtime(&root, &mut max_mtime);
+    #[test]
+    fn test_time_format() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().join("root");
+        std::fs::create_dir(&root).unwrap();
+        filetime::set_file_mtime(&root, filetime::FileTime::from_unix_time(1_000_000_000, 0)).unwrap();
 
         let mut cmd = assert_cmd::Command::cargo_bin("maxtime").unwrap();
 
-        cmd.arg(root);
-        cmd.assert().success();
-        cmd.assert().stdout(format!(
-            "{}\n",
-            time::OffsetDateTime::from(max_mtime).unix_timestamp_nanos()
+        cmd.arg("--time-format").arg("rfc3339").arg("--utc").arg(&root);
+        cmd.assert().success().stdout("2001-09-09T01:46:40Z\n");
+
+        let mut cmd = assert_cmd::Command::cargo_bin("maxtime").unwrap();
+
+        cmd.arg("--time-format").arg("[year]").arg("--local").arg(&root);
+        cmd.assert().success().stdout("2001\n");
+
+        // The last of `--utc` and `--local` wins.
+        for (args, expected) in [
+            (["--local", "--utc"], "2001-09-09T01:46:40Z\n"),
+            (["--utc", "--local"], "2001-09-09T10:46:40+09:00\n"),
+        ] {
+            let mut cmd = assert_cmd::Command::cargo_bin("maxtime").unwrap();
+            cmd.env("TZ", "JST-9").arg("--time-format").arg("rfc3339").args(args).arg(&root);
+            cmd.assert().success().stdout(expected);
+        }
+    }
+
+    #[test]
+    fn test_hidden() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().join("root");
+        std::fs::create_dir(&root).unwrap();
+        std::fs::write(root.join(".env"), "").unwrap();
+        filetime::set_file_mtime(root.join(".env"), filetime::FileTime::from_unix_time(2, 0)).unwrap();
+        filetime::set_file_mtime(&root, filetime::FileTime::from_unix_time(1, 0)).unwrap();
+
+        let mut cmd = assert_cmd::Command::cargo_bin("maxtime").unwrap();
+
+        cmd.arg(&root);
+        cmd.assert().success().stdout("1000000000\n");
+
+        let mut cmd = assert_cmd::Command::cargo_bin("maxtime").unwrap();
+
+        cmd.arg("--hidden").arg("--show-path").arg(&root);
+        cmd.assert().success().stdout("2000000000 .env\n");
+    }
+
+    #[test]
+    fn test_per_root() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let a = temp_dir.path().join("a");
+        let b = temp_dir.path().join("b");
+        std::fs::create_dir(&a).unwrap();
+        std::fs::write(&b, "").unwrap();
+        filetime::set_file_mtime(&a, filetime::FileTime::from_unix_time(1, 0)).unwrap();
+        filetime::set_file_mtime(&b, filetime::FileTime::from_unix_time(2, 0)).unwrap();
+
+        let mut cmd = assert_cmd::Command::cargo_bin("maxtime").unwrap();
+
+        cmd.arg("--show-path").arg("--per-root").arg(&a).arg(&b);
+        cmd.assert().success().stdout(format!(
+            "2000000000 {b}\n1000000000 {a}\n2000000000 {b}\n",
+            a = a.display(),
+            b = b.display()
         ));
     }
+
+    #[test]
+    fn test_newer_than() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().join("root");
+        std::fs::create_dir(&root).unwrap();
+        let reference = temp_dir.path().join("reference");
+        for (path, secs) in [(root.join("old"), 100), (root.join("new"), 300), (root.join("newer"), 400), (reference.clone(), 200)] {
+            std::fs::write(&path, "").unwrap();
+            let mtime = UNIX_EPOCH + Duration::from_secs(secs);
+            filetime::set_file_mtime(&path, filetime::FileTime::from_system_time(mtime)).unwrap();
+        }
+        filetime::set_file_mtime(&root, filetime::FileTime::from_system_time(UNIX_EPOCH)).unwrap();
+
+        let expected = format!("{}\n{}\n", root.join("new").display(), root.join("newer").display());
+        let file_reference = format!("@{}", reference.display());
+        for reference in ["200000000000", "1970-01-01T00:03:20Z", &file_reference] {
+            let mut cmd = assert_cmd::Command::cargo_bin("maxtime").unwrap();
+            cmd.arg("--newer-than").arg(reference).arg(&root);
+            cmd.assert().success().stdout(expected.clone());
+        }
+
+        let mut cmd = assert_cmd::Command::cargo_bin("maxtime").unwrap();
+        cmd.arg("--newer-than").arg("200000000000").arg("-0").arg(&root);
+        cmd.assert().success().stdout(expected.replace('\n', "\0"));
+
+        let mut cmd = assert_cmd::Command::cargo_bin("maxtime").unwrap();
+        cmd.arg("--newer-than").arg("yesterday").arg(&root);
+        cmd.assert().code(2);
+    }
+
+    #[test]
+    fn test_manifest() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().join("root");
+        std::fs::create_dir(&root).unwrap();
+        let file = root.join("file");
+        std::fs::write(&file, "").unwrap();
+        let manifest = temp_dir.path().join("files.manifest");
+
+        let set_mtime = |secs| {
+            let mtime = UNIX_EPOCH + Duration::from_secs(secs);
+            filetime::set_file_mtime(&file, filetime::FileTime::from_system_time(mtime)).unwrap();
+        };
+        set_mtime(100);
+
+        let mut cmd = assert_cmd::Command::cargo_bin("maxtime").unwrap();
+        cmd.arg("manifest").arg("save").arg(&manifest).arg(&root);
+        cmd.assert().success().stdout("Recorded 1 file(s)\n");
+
+        set_mtime(200);
+        let mut cmd = assert_cmd::Command::cargo_bin("maxtime").unwrap();
+        cmd.arg("manifest").arg("restore").arg(&manifest).arg(&root);
+        cmd.assert()
+            .success()
+            .stdout("Restored 1 mtime(s), 0 already up to date, 0 changed and 0 new file(s) skipped\n");
+        assert_eq!(std::fs::metadata(&file).unwrap().modified().unwrap(), UNIX_EPOCH + Duration::from_secs(100));
+
+        let diff = || {
+            let mut cmd = assert_cmd::Command::cargo_bin("maxtime").unwrap();
+            cmd.arg("diff").arg(&manifest).arg(&root);
+            cmd.assert()
+        };
+        diff().code(0).stdout("");
+
+        std::fs::write(&file, "new").unwrap();
+        std::fs::write(root.join("added"), "").unwrap();
+        diff().code(1).stdout("added added\nmodified file (mtime, size, contents)\n");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_watch() {
+        use std::io::BufRead;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().join("root");
+        std::fs::create_dir(&root).unwrap();
+        let file = root.join("file");
+        std::fs::write(&file, "").unwrap();
+        let stamp = temp_dir.path().join("out.stamp");
+
+        let set_mtime = |path: &std::path::Path, secs| {
+            let mtime = UNIX_EPOCH + Duration::from_secs(secs);
+            filetime::set_file_mtime(path, filetime::FileTime::from_system_time(mtime)).unwrap();
+        };
+        set_mtime(&file, 100);
+        set_mtime(&root, 0);
+
+        let mut child = std::process::Command::new(assert_cmd::cargo::cargo_bin("maxtime"))
+            .arg("watch")
+            .arg("--debounce")
+            .arg("10")
+            .arg("--stamp")
+            .arg(&stamp)
+            .arg(&root)
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut lines = std::io::BufReader::new(child.stdout.take().unwrap()).lines();
+
+        assert_eq!(lines.next().unwrap().unwrap(), "100000000000");
+        assert_eq!(std::fs::read_to_string(&stamp).unwrap(), "100000000000\n");
+
+        set_mtime(&file, 200);
+        assert_eq!(lines.next().unwrap().unwrap(), "200000000000");
+        assert_eq!(std::fs::read_to_string(&stamp).unwrap(), "200000000000\n");
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_watch_stamp_in_tree() {
+        use std::io::BufRead;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().join("root");
+        std::fs::create_dir(&root).unwrap();
+        let file = root.join("file");
+        std::fs::write(&file, "").unwrap();
+        let stamp = root.join("out.stamp");
+        filetime::set_file_mtime(&file, filetime::FileTime::from_unix_time(100, 0)).unwrap();
+        filetime::set_file_mtime(&root, filetime::FileTime::from_unix_time(0, 0)).unwrap();
+
+        let mut child = std::process::Command::new(assert_cmd::cargo::cargo_bin("maxtime"))
+            .arg("watch")
+            .arg("--debounce")
+            .arg("10")
+            .arg("--stamp")
+            .arg(&stamp)
+            .arg(&root)
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut lines = std::io::BufReader::new(child.stdout.take().unwrap()).lines();
+
+        // Writing the stamp doesn't count as a change, so the next line is for
+        // the next real change.
+        assert_eq!(lines.next().unwrap().unwrap(), "100000000000");
+        std::thread::sleep(Duration::from_millis(200));
+        filetime::set_file_mtime(&file, filetime::FileTime::from_unix_time(200, 0)).unwrap();
+        assert_eq!(lines.next().unwrap().unwrap(), "200000000000");
+        assert_eq!(std::fs::read_to_string(&stamp).unwrap(), "200000000000\n");
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
 }
@@ -0,0 +1,398 @@
+//! Manifests record the size, content hash and mtime of every file in a tree,
+//! so that the mtimes can be put back after the tree is recreated with new
+//! ones (e.g. by a fresh checkout in CI) without hiding real changes.
+//!
+//! The format is text, one file per line after a header:
+//!
+//! ```text
+//! maxtime-manifest 1
+//! <mtime nanos> <size> <sha256 hex> <path>
+//! ```
+//!
+//! Paths are relative to the scanned path, or as walked if several paths were
+//! scanned, and escaped like paths in the cache so they never contain spaces
+//! or newlines. Lines are sorted by path.
+
+use std::cmp::Ordering;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::cache::{escape, unescape};
+use crate::{json, Entry, EntryFilter, ScanResult, Scanner, TimeKind, JSON_VERSION};
+
+const HEADER: &str = "maxtime-manifest 1";
+
+/// A file recorded in a manifest.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ManifestFile {
+    /// Path relative to the scanned path, or as walked if several paths were
+    /// scanned.
+    pub path: PathBuf,
+    pub mtime: SystemTime,
+    /// Size in bytes.
+    pub size: u64,
+    /// SHA-256 of the contents, as 64 hex digits.
+    pub hash: String,
+}
+
+/// The files in a tree, sorted by path.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Manifest {
+    pub files: Vec<ManifestFile>,
+}
+
+/// The differences between two manifests. Each list is sorted by path.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Diff {
+    /// Files that are only in the new manifest.
+    pub added: Vec<ManifestFile>,
+    /// Files that are only in the old manifest.
+    pub removed: Vec<ManifestFile>,
+    /// Files whose mtime, size or contents changed, as (old, new).
+    pub modified: Vec<(ManifestFile, ManifestFile)>,
+}
+
+impl Diff {
+    /// True if nothing changed.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+
+    /// Encode the differences as a single line of JSON, in the same style as
+    /// `ScanResult::to_json()`.
+    pub fn to_json(&self) -> Result<String> {
+        let file_fields = |file: &ManifestFile| -> Result<Vec<(&'static str, String)>> {
+            Ok(vec![
+                ("mtime_nanos", json::nanos(file.mtime)),
+                ("mtime", json::rfc3339(file.mtime)?),
+                ("size", file.size.to_string()),
+                ("hash", json::string(&file.hash)),
+            ])
+        };
+        let file = |file: &ManifestFile| -> Result<String> {
+            let mut fields = vec![("path", json::path(&file.path))];
+            fields.extend(file_fields(file)?);
+            Ok(json::object(fields))
+        };
+        let added = self.added.iter().map(file).collect::<Result<Vec<_>>>()?;
+        let removed = self.removed.iter().map(file).collect::<Result<Vec<_>>>()?;
+        let modified = self
+            .modified
+            .iter()
+            .map(|(old, new)| {
+                Ok(json::object([
+                    ("path", json::path(&new.path)),
+                    ("old", json::object(file_fields(old)?)),
+                    ("new", json::object(file_fields(new)?)),
+                ]))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(json::object([
+            ("version", JSON_VERSION.to_string()),
+            ("added", json::array(added)),
+            ("removed", json::array(removed)),
+            ("modified", json::array(modified)),
+        ]))
+    }
+}
+
+/// What `Manifest::restore()` did.
+#[derive(Debug)]
+pub struct Restored {
+    /// Number of files whose mtimes were set.
+    pub restored: u64,
+    /// Number of files that already had the recorded mtime.
+    pub unchanged: u64,
+    /// Number of files whose size or contents are different from the
+    /// manifest, which are left alone.
+    pub changed: u64,
+    /// Number of files that aren't in the manifest, which are left alone.
+    pub added: u64,
+    /// The scan that found the files. Its errors haven't been checked.
+    pub scan: ScanResult,
+}
+
+impl Manifest {
+    /// Record every regular file found by `scanner`, reading all of them to
+    /// hash their contents. Also returns the scan, whose errors haven't been
+    /// checked.
+    pub fn scan(scanner: Scanner) -> Result<(Self, ScanResult)> {
+        let scan = scan_files(scanner)?;
+        let files = scan
+            .entry_list
+            .iter()
+            .filter_map(|entry| manifest_file(&scan, entry))
+            .collect();
+        Ok((Self { files }, scan))
+    }
+
+    /// The file with the given path, if there is one.
+    pub fn get(&self, path: &Path) -> Option<&ManifestFile> {
+        self.files
+            .binary_search_by(|file| file.path.as_path().cmp(path))
+            .ok()
+            .map(|index| &self.files[index])
+    }
+
+    /// Set the mtime of every regular file found by `scanner` back to the one
+    /// in the manifest, if its size and contents are the same as recorded.
+    /// The paths should be the same as when the manifest was saved.
+    pub fn restore(&self, scanner: Scanner) -> Result<Restored> {
+        let scan = scan_files(scanner)?;
+        let mut restored = 0;
+        let mut unchanged = 0;
+        let mut changed = 0;
+        let mut added = 0;
+        for entry in &scan.entry_list {
+            let Some(current) = manifest_file(&scan, entry) else {
+                continue;
+            };
+            match self.get(&current.path) {
+                None => added += 1,
+                Some(recorded) if recorded.size != current.size || recorded.hash != current.hash => changed += 1,
+                Some(recorded) if recorded.mtime == current.mtime => unchanged += 1,
+                Some(recorded) => {
+                    filetime::set_file_mtime(&entry.path, filetime::FileTime::from_system_time(recorded.mtime))
+                        .with_context(|| anyhow!("error setting mtime of {}", entry.path.display()))?;
+                    restored += 1;
+                }
+            }
+        }
+        Ok(Restored {
+            restored,
+            unchanged,
+            changed,
+            added,
+            scan,
+        })
+    }
+
+    /// The differences from this manifest to `new`.
+    pub fn diff(&self, new: &Manifest) -> Diff {
+        let mut diff = Diff::default();
+        let mut old_files = self.files.iter().peekable();
+        let mut new_files = new.files.iter().peekable();
+        loop {
+            let order = match (old_files.peek(), new_files.peek()) {
+                (None, None) => return diff,
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some(old), Some(new)) => old.path.cmp(&new.path),
+            };
+            match order {
+                Ordering::Less => diff.removed.extend(old_files.next().cloned()),
+                Ordering::Greater => diff.added.extend(new_files.next().cloned()),
+                Ordering::Equal => {
+                    let (old, new) = (old_files.next().unwrap(), new_files.next().unwrap());
+                    if old != new {
+                        diff.modified.push((old.clone(), new.clone()));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Read a manifest written by `save()`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents =
+            std::fs::read_to_string(path).with_context(|| anyhow!("error reading manifest {}", path.display()))?;
+        Self::parse(&contents).with_context(|| anyhow!("invalid manifest {}", path.display()))
+    }
+
+    fn parse(contents: &str) -> Result<Self> {
+        let mut lines = contents.lines();
+        if lines.next() != Some(HEADER) {
+            bail!("expected \"{}\" on the first line", HEADER);
+        }
+        let mut files = lines
+            .enumerate()
+            .map(|(index, line)| {
+                parse_line(line).ok_or_else(|| anyhow!("invalid line {}: {}", index + 2, line))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(Self { files })
+    }
+
+    /// Write the manifest atomically, creating missing parent directories.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut out = format!("{}\n", HEADER);
+        for file in &self.files {
+            let escaped = escape(file.path.as_os_str())
+                .ok_or_else(|| anyhow!("can't save non-Unicode path {} in the manifest", file.path.display()))?;
+            out.push_str(&format!(
+                "{} {} {} {}\n",
+                time::OffsetDateTime::from(file.mtime).unix_timestamp_nanos(),
+                file.size,
+                file.hash,
+                escaped
+            ));
+        }
+        crate::stamp::write_atomic(path, out.as_bytes(), None, "manifest")
+    }
+}
+
+fn parse_line(line: &str) -> Option<ManifestFile> {
+    let mut fields = line.split(' ');
+    let mtime = fields.next()?.parse().ok()?;
+    let size = fields.next()?.parse().ok()?;
+    let hash = fields.next()?;
+    let path = unescape(fields.next()?)?;
+    if fields.next().is_some() || hash.len() != 64 || !hash.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some(ManifestFile {
+        path: path.into(),
+        mtime: time::OffsetDateTime::from_unix_timestamp_nanos(mtime).ok()?.into(),
+        size,
+        hash: hash.to_owned(),
+    })
+}
+
+// Scan for the files to record, hashing their contents.
+fn scan_files(mut scanner: Scanner) -> Result<ScanResult> {
+    scanner
+        .time_kind(TimeKind::Mtime)
+        .entry_filter(EntryFilter::Files)
+        .collect_entries(true)
+        .hash_entries(true)
+        .scan()
+}
+
+// What to record for an entry, or `None` if it isn't a regular file. The path
+// is relative to the scanned path if there is only one, like `--show-path`.
+fn manifest_file(scan: &ScanResult, entry: &Entry) -> Option<ManifestFile> {
+    if !entry.file_type.is_file() {
+        return None;
+    }
+    let path = match scan.roots.as_slice() {
+        [root] => match entry.path.strip_prefix(&root.path) {
+            Ok(relative) if relative.as_os_str().is_empty() => PathBuf::from("."),
+            Ok(relative) => relative.to_owned(),
+            Err(_) => entry.path.clone(),
+        },
+        _ => entry.path.clone(),
+    };
+    Some(ManifestFile {
+        path,
+        mtime: entry.time,
+        size: entry.size,
+        hash: entry.hash.clone()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn set_mtime(path: &Path, secs: u64) {
+        filetime::set_file_mtime(path, filetime::FileTime::from_system_time(UNIX_EPOCH + Duration::from_secs(secs)))
+            .unwrap();
+    }
+
+    fn mtime(path: &Path) -> u64 {
+        let mtime = std::fs::metadata(path).unwrap().modified().unwrap();
+        mtime.duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    #[test]
+    fn test_save_restore() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().join("root");
+        std::fs::create_dir_all(root.join("dir")).unwrap();
+        for (file, contents) in [("same", "s"), ("dir/with space", "w"), ("edited", "e"), ("resized", "r")] {
+            std::fs::write(root.join(file), contents).unwrap();
+            set_mtime(&root.join(file), 100);
+        }
+        let manifest_path = temp_dir.path().join("files.manifest");
+
+        let (manifest, scan) = Manifest::scan(Scanner::new(&root)).unwrap();
+        assert!(scan.errors.is_empty());
+        assert_eq!(manifest.files.len(), 4);
+        assert_eq!(manifest.files[0].path, Path::new("dir/with space"));
+        manifest.save(&manifest_path).unwrap();
+        assert_eq!(Manifest::load(&manifest_path).unwrap(), manifest);
+
+        // As if checked out again, with some changes.
+        std::fs::write(root.join("edited"), "E").unwrap();
+        std::fs::write(root.join("resized"), "rr").unwrap();
+        std::fs::write(root.join("added"), "a").unwrap();
+        for file in ["same", "dir/with space", "edited", "resized", "added"] {
+            set_mtime(&root.join(file), 200);
+        }
+
+        let restored = manifest.restore(Scanner::new(&root)).unwrap();
+        assert_eq!(
+            (restored.restored, restored.unchanged, restored.changed, restored.added),
+            (2, 0, 2, 1)
+        );
+        assert_eq!(mtime(&root.join("same")), 100);
+        assert_eq!(mtime(&root.join("dir/with space")), 100);
+        assert_eq!(mtime(&root.join("edited")), 200);
+        assert_eq!(mtime(&root.join("resized")), 200);
+        assert_eq!(mtime(&root.join("added")), 200);
+
+        let restored = manifest.restore(Scanner::new(&root)).unwrap();
+        assert_eq!(
+            (restored.restored, restored.unchanged, restored.changed, restored.added),
+            (0, 2, 2, 1)
+        );
+    }
+
+    #[test]
+    fn test_diff() {
+        let file = |path: &str, secs, size, hash: char| ManifestFile {
+            path: path.into(),
+            mtime: UNIX_EPOCH + Duration::from_secs(secs),
+            size,
+            hash: hash.to_string().repeat(64),
+        };
+        let old = Manifest {
+            files: vec![file("a", 1, 1, 'a'), file("b", 1, 1, 'b'), file("c", 1, 1, 'c'), file("d", 1, 1, 'd')],
+        };
+        let new = Manifest {
+            files: vec![file("0", 1, 1, '0'), file("b", 2, 1, 'b'), file("c", 1, 1, 'c'), file("e", 1, 2, 'e')],
+        };
+
+        assert!(old.diff(&old).is_empty());
+        let diff = old.diff(&new);
+        assert_eq!(diff.added, [file("0", 1, 1, '0'), file("e", 1, 2, 'e')]);
+        assert_eq!(diff.removed, [file("a", 1, 1, 'a'), file("d", 1, 1, 'd')]);
+        assert_eq!(diff.modified, [(file("b", 1, 1, 'b'), file("b", 2, 1, 'b'))]);
+
+        let diff = Manifest::default().diff(&Manifest {
+            files: vec![file("a", 1, 1, 'a')],
+        });
+        assert_eq!(
+            diff.to_json().unwrap(),
+            format!(
+                r#"{{"version":1,"added":[{{"path":"a","mtime_nanos":1000000000,"mtime":"1970-01-01T00:00:01Z","size":1,"hash":"{}"}}],"removed":[],"modified":[]}}"#,
+                "a".repeat(64)
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let hash = "0".repeat(64);
+        let manifest = Manifest::parse(&format!("{}\n-5 3 {} a%20b\n", HEADER, hash)).unwrap();
+        assert_eq!(manifest.files[0].path, Path::new("a b"));
+        assert_eq!(manifest.files[0].mtime, UNIX_EPOCH - Duration::from_nanos(5));
+        assert_eq!(manifest.files[0].size, 3);
+
+        for contents in [
+            String::new(),
+            "maxtime-manifest 2\n".to_owned(),
+            format!("{}\n1 2 {} a extra\n", HEADER, hash),
+            format!("{}\n1 2 abc a\n", HEADER),
+            format!("{}\n1 x {} a\n", HEADER, hash),
+        ] {
+            assert!(Manifest::parse(&contents).is_err(), "{:?}", contents);
+        }
+    }
+}
@@ -0,0 +1,1926 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::fmt;
+use std::io::Read;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Context, Result};
+use ignore::overrides::OverrideBuilder;
+use ignore::WalkBuilder;
+use sha2::{Digest, Sha256};
+
+use crate::cache::{self, Cache, Child, DirRecord, DirStat};
+use crate::git::CommitTimes;
+use crate::hash::{self, SetDigest};
+use crate::{json, TimeKind};
+
+/// Version of the JSON produced by `ScanResult::to_json()` and
+/// `manifest::Diff::to_json()`. It changes when fields are removed or change
+/// meaning, but not when fields are added.
+pub const JSON_VERSION: u32 = 1;
+
+/// Which kinds of entry count towards the maximum time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EntryFilter {
+    /// Everything.
+    #[default]
+    All,
+    /// Everything except directories, i.e. files, symlinks and special files.
+    /// A directory's mtime changes whenever an entry in it is created or
+    /// deleted, even if that entry is ignored (e.g. editor swap files), so
+    /// this avoids spurious changes.
+    Files,
+    /// Only directories.
+    Dirs,
+}
+
+impl EntryFilter {
+    fn matches(self, file_type: Option<std::fs::FileType>) -> bool {
+        let is_dir = file_type.is_some_and(|file_type| file_type.is_dir());
+        match self {
+            Self::All => true,
+            Self::Files => !is_dir,
+            Self::Dirs => is_dir,
+        }
+    }
+}
+
+/// Scans directory trees in parallel, respecting `.gitignore` files.
+pub struct Scanner {
+    paths: Vec<PathBuf>,
+    // Globs for `OverrideBuilder`. Excludes are prefixed with `!`.
+    overrides: Vec<String>,
+    ignore_file_names: Vec<String>,
+    ignore_files: Vec<PathBuf>,
+    time_kind: TimeKind,
+    entry_filter: EntryFilter,
+    top: usize,
+    quit_if_newer_than: Option<SystemTime>,
+    strict: bool,
+    keep_going: bool,
+    fingerprint: bool,
+    hash: bool,
+    cache: Option<PathBuf>,
+    collect_dirs: bool,
+    collect_entries: bool,
+    collect_newer_than: Option<SystemTime>,
+    hash_entries: bool,
+    // See `cache::RACY_WINDOW`. Only changed by tests.
+    racy_window: Duration,
+    // Passed straight to `WalkBuilder`.
+    hidden: bool,
+    parents: bool,
+    ignore: bool,
+    git_ignore: bool,
+    git_global: bool,
+    git_exclude: bool,
+    require_git: bool,
+    max_depth: Option<usize>,
+    same_file_system: bool,
+}
+
+impl Scanner {
+    /// Create a scanner for the given path.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            paths: vec![path.into()],
+            overrides: Vec::new(),
+            ignore_file_names: Vec::new(),
+            ignore_files: Vec::new(),
+            time_kind: TimeKind::Mtime,
+            entry_filter: EntryFilter::All,
+            top: 0,
+            quit_if_newer_than: None,
+            strict: false,
+            keep_going: false,
+            fingerprint: false,
+            hash: false,
+            cache: None,
+            collect_dirs: false,
+            collect_entries: false,
+            collect_newer_than: None,
+            hash_entries: false,
+            racy_window: cache::RACY_WINDOW,
+            hidden: true,
+            parents: true,
+            ignore: true,
+            git_ignore: true,
+            git_global: true,
+            git_exclude: true,
+            require_git: true,
+            max_depth: None,
+            same_file_system: false,
+        }
+    }
+
+    /// Add another path to scan. It can be a directory or a file. All the
+    /// paths are scanned in one parallel walk.
+    pub fn add(&mut self, path: impl Into<PathBuf>) -> &mut Self {
+        self.paths.push(path.into());
+        self
+    }
+
+    /// The paths to scan.
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    /// Only scan files that match this glob. Directories are still descended
+    /// into, and can be included or excluded with globs that end in `/`.
+    ///
+    /// Globs use `.gitignore` syntax, relative to the current directory, and
+    /// take precedence over ignore files: a file that matches an include glob
+    /// is scanned even if it is ignored by a `.gitignore`. If there are no
+    /// include globs then all files not otherwise ignored are scanned.
+    pub fn include(&mut self, glob: impl Into<String>) -> &mut Self {
+        self.overrides.push(glob.into());
+        self
+    }
+
+    /// Don't scan files or directories that match this glob, even if they
+    /// match an include glob. See `include()` for the syntax.
+    pub fn exclude(&mut self, glob: impl Into<String>) -> &mut Self {
+        self.overrides.push(format!("!{}", glob.into()));
+        self
+    }
+
+    /// Also respect ignore files with this name, e.g. `.maxtimeignore` or
+    /// `.dockerignore`. They use `.gitignore` syntax and are read in every
+    /// directory like `.gitignore` files, even outside a git repository.
+    ///
+    /// They take precedence over all the standard ignore files, so they can
+    /// whitelist gitignored files with `!`. If several names are added, later
+    /// ones take precedence over earlier ones.
+    pub fn ignore_file_name(&mut self, name: impl Into<String>) -> &mut Self {
+        self.ignore_file_names.push(name.into());
+        self
+    }
+
+    /// Also respect this ignore file for the whole scan, like git's global
+    /// excludes file. Globs without a `/` match anywhere, and globs with one
+    /// match paths as walked (i.e. starting with the scanned path).
+    ///
+    /// These have the lowest precedence of all ignore files, so `.gitignore`
+    /// and the other per-directory ignore files can whitelist things that
+    /// they ignore.
+    pub fn ignore_file(&mut self, path: impl Into<PathBuf>) -> &mut Self {
+        self.ignore_files.push(path.into());
+        self
+    }
+
+    /// Which timestamp to use. The default is the mtime.
+    pub fn time_kind(&mut self, time_kind: TimeKind) -> &mut Self {
+        self.time_kind = time_kind;
+        self
+    }
+
+    /// Which kinds of entry count towards the maximum. Directories are always
+    /// descended into; this only controls whether their own times count. The
+    /// default is all entries.
+    pub fn entry_filter(&mut self, entry_filter: EntryFilter) -> &mut Self {
+        self.entry_filter = entry_filter;
+        self
+    }
+
+    /// Also collect the `n` newest entries into `ScanResult::top`.
+    pub fn top(&mut self, n: usize) -> &mut Self {
+        self.top = n;
+        self
+    }
+
+    /// Stop the walk as soon as an entry newer than `time` is found. The
+    /// result then only covers the entries visited so far, but its `max_time`
+    /// is still newer than `time`, which is enough to tell that something
+    /// changed.
+    pub fn quit_if_newer_than(&mut self, time: SystemTime) -> &mut Self {
+        self.quit_if_newer_than = Some(time);
+        self
+    }
+
+    /// Treat entries that disappear during the walk as errors. By default
+    /// they are skipped and counted in `ScanResult::vanished`, because files
+    /// are often deleted between listing a directory and reading their
+    /// metadata when scanning a tree while a build is running. The scanned
+    /// paths themselves must always exist.
+    pub fn strict(&mut self, yes: bool) -> &mut Self {
+        self.strict = yes;
+        self
+    }
+
+    /// Carry on after errors, e.g. unreadable directories, instead of stopping
+    /// the walk at the first one. All of the errors are collected in
+    /// `ScanResult::errors` and the result covers the entries that could be
+    /// read.
+    pub fn keep_going(&mut self, yes: bool) -> &mut Self {
+        self.keep_going = yes;
+        self
+    }
+
+    /// Also compute `ScanResult::fingerprint`, a digest of the path, type,
+    /// size and time of every entry counted. Unlike the max time it changes
+    /// when an entry is deleted or renamed, or an old file is added.
+    pub fn fingerprint(&mut self, yes: bool) -> &mut Self {
+        self.fingerprint = yes;
+        self
+    }
+
+    /// Also compute `ScanResult::hash`, a hash of the contents and paths of
+    /// every entry counted. Unlike the max time and the fingerprint it doesn't
+    /// change if files are rewritten with the same contents, e.g. by a fresh
+    /// clone or a cache restore. This reads every file so it is much slower.
+    pub fn hash(&mut self, yes: bool) -> &mut Self {
+        self.hash = yes;
+        self
+    }
+
+    /// Use a cache file to speed up later scans. It records the filtered
+    /// listing of each directory along with the directory's mtime and ctime,
+    /// which change whenever an entry in it is created, deleted or renamed.
+    /// Directories whose times haven't changed aren't read again; only the
+    /// entries in them are stat'd. Changed directories are walked as usual.
+    ///
+    /// The cache is only used if it was written with the same paths and
+    /// filter options, and is ignored if it is invalid in any way, in which
+    /// case everything is walked. It is rewritten after every complete scan
+    /// in which anything changed. Changes to the global gitignore file aren't
+    /// noticed, and it is not used if `parents()` is disabled because changed
+    /// directories are walked on their own.
+    ///
+    /// Only Unix has ctimes, so elsewhere nothing is cached and every
+    /// directory is read.
+    pub fn cache(&mut self, path: impl Into<PathBuf>) -> &mut Self {
+        self.cache = Some(path.into());
+        self
+    }
+
+    /// Also collect the paths of all the directories scanned into
+    /// `ScanResult::dirs`, e.g. to watch them for changes.
+    pub fn collect_dirs(&mut self, yes: bool) -> &mut Self {
+        self.collect_dirs = yes;
+        self
+    }
+
+    /// Also collect every entry that counts towards the maximum time into
+    /// `ScanResult::entry_list`, with its type, size and time.
+    pub fn collect_entries(&mut self, yes: bool) -> &mut Self {
+        self.collect_entries = yes;
+        self
+    }
+
+    /// With `collect_entries()`, only collect entries whose time is after
+    /// `time`, like `find -newer`. They all still count towards the maximum.
+    pub fn collect_newer_than(&mut self, time: SystemTime) -> &mut Self {
+        self.collect_newer_than = Some(time);
+        self
+    }
+
+    /// With `collect_entries()`, also hash the contents of each entry into
+    /// `Entry::hash`. This reads every file so it is much slower.
+    pub fn hash_entries(&mut self, yes: bool) -> &mut Self {
+        self.hash_entries = yes;
+        self
+    }
+
+    /// Skip hidden files and directories, i.e. those whose names start with
+    /// `.`. Enabled by default, so e.g. `.env` and `.cargo/config.toml` are
+    /// not scanned unless this is disabled.
+    pub fn hidden(&mut self, yes: bool) -> &mut Self {
+        self.hidden = yes;
+        self
+    }
+
+    /// Read ignore files from the parent directories of the scanned paths.
+    /// Enabled by default.
+    pub fn parents(&mut self, yes: bool) -> &mut Self {
+        self.parents = yes;
+        self
+    }
+
+    /// Respect `.ignore` files. Enabled by default.
+    pub fn ignore(&mut self, yes: bool) -> &mut Self {
+        self.ignore = yes;
+        self
+    }
+
+    /// Respect `.gitignore` files. Enabled by default.
+    pub fn git_ignore(&mut self, yes: bool) -> &mut Self {
+        self.git_ignore = yes;
+        self
+    }
+
+    /// Respect the global gitignore file set by git's `core.excludesFile`.
+    /// Enabled by default.
+    pub fn git_global(&mut self, yes: bool) -> &mut Self {
+        self.git_global = yes;
+        self
+    }
+
+    /// Respect `.git/info/exclude` files. Enabled by default.
+    pub fn git_exclude(&mut self, yes: bool) -> &mut Self {
+        self.git_exclude = yes;
+        self
+    }
+
+    /// Only respect git's ignore files inside a git repository. Enabled by
+    /// default; disable it to use `.gitignore` files in trees that aren't
+    /// checked out with git, e.g. source tarballs.
+    pub fn require_git(&mut self, yes: bool) -> &mut Self {
+        self.require_git = yes;
+        self
+    }
+
+    /// Don't descend more than `depth` directories below the scanned paths.
+    /// A depth of 0 only scans the paths themselves. There is no limit by
+    /// default.
+    pub fn max_depth(&mut self, depth: Option<usize>) -> &mut Self {
+        self.max_depth = depth;
+        self
+    }
+
+    /// Don't cross filesystem boundaries, e.g. into mounted volumes. Disabled
+    /// by default.
+    pub fn same_file_system(&mut self, yes: bool) -> &mut Self {
+        self.same_file_system = yes;
+        self
+    }
+
+    // True if entries of this type count towards the maximum. Git doesn't
+    // track directories, so they don't have commit times.
+    fn counts(&self, file_type: Option<std::fs::FileType>) -> bool {
+        let is_dir = file_type.is_some_and(|file_type| file_type.is_dir());
+        self.entry_filter.matches(file_type) && !(self.time_kind == TimeKind::GitCommit && is_dir)
+    }
+
+    /// Set up a walk of the given paths with the configured filters.
+    pub(crate) fn walk_builder(&self, paths: &[PathBuf]) -> Result<WalkBuilder> {
+        let mut walk_builder = WalkBuilder::new(&paths[0]);
+        for path in &paths[1..] {
+            walk_builder.add(path);
+        }
+        walk_builder
+            .hidden(self.hidden)
+            .parents(self.parents)
+            .ignore(self.ignore)
+            .git_ignore(self.git_ignore)
+            .git_global(self.git_global)
+            .git_exclude(self.git_exclude)
+            .require_git(self.require_git)
+            .max_depth(self.max_depth)
+            .same_file_system(self.same_file_system);
+        for name in &self.ignore_file_names {
+            walk_builder.add_custom_ignore_filename(name);
+        }
+        for path in &self.ignore_files {
+            if let Some(e) = walk_builder.add_ignore(path) {
+                return Err(e).with_context(|| anyhow!("error reading ignore file {}", path.display()));
+            }
+        }
+
+        if !self.overrides.is_empty() {
+            let current_dir = std::env::current_dir().context("error getting current directory")?;
+            let mut override_builder = OverrideBuilder::new(current_dir);
+            for glob in &self.overrides {
+                override_builder
+                    .add(glob)
+                    .with_context(|| anyhow!("invalid glob {}", glob.trim_start_matches('!')))?;
+            }
+            walk_builder.overrides(override_builder.build().context("error building globs")?);
+        }
+
+        Ok(walk_builder)
+    }
+
+    /// Walk the trees and find the maximum time. This only fails if the walk
+    /// couldn't be started; errors during the walk are in the result.
+    pub fn scan(&self) -> Result<ScanResult> {
+        for path in &self.paths {
+            std::fs::symlink_metadata(path).with_context(|| anyhow!("error reading path {}", path.display()))?;
+        }
+
+        let commit_times = match self.time_kind {
+            TimeKind::GitCommit => Some(CommitTimes::load(&self.paths)?),
+            _ => None,
+        };
+        let totals = Arc::new(Mutex::new(Totals::new(self)));
+        match &self.cache {
+            Some(cache_path) => self.scan_cached(cache_path, commit_times.as_ref(), &totals)?,
+            None => {
+                let walk_roots: Vec<_> = self.paths.iter().cloned().zip(0..).collect();
+                self.walk(&walk_roots, self.max_depth, commit_times.as_ref(), &totals)?;
+            }
+        }
+        let totals = std::mem::replace(&mut *totals.lock().unwrap(), Totals::new(self));
+
+        // Sort so the output doesn't depend on thread timing.
+        let mut errors = totals.errors;
+        errors.sort_by(|a, b| a.path.cmp(&b.path));
+        let mut dirs = totals.dirs;
+        dirs.sort();
+        let mut entry_list = totals.entry_list;
+        entry_list.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let mut all = RootResult::new(PathBuf::new());
+        for root in &totals.roots {
+            all.merge(root);
+        }
+
+        Ok(ScanResult {
+            max_time: all.max_time,
+            max_path: all.max_path,
+            top: totals
+                .top
+                .into_sorted_vec()
+                .into_iter()
+                .map(|Reverse((time, Reverse(path)))| (path, time))
+                .collect(),
+            entries: all.entries,
+            fingerprint: self.fingerprint.then(|| totals.fingerprint.to_hex()),
+            hash: self.hash.then(|| totals.hash.to_hex()),
+            vanished: totals.vanished,
+            cached_dirs: totals.cached_dirs,
+            dirs,
+            entry_list,
+            roots: totals.roots,
+            errors,
+        })
+    }
+
+    // Walk from each of `walk_roots`, which are paired with the index of the
+    // scanned path they are under, adding to `totals`. `commit_times` is only
+    // given for `TimeKind::GitCommit`.
+    fn walk(
+        &self,
+        walk_roots: &[(PathBuf, usize)],
+        max_depth: Option<usize>,
+        commit_times: Option<&CommitTimes>,
+        totals: &Arc<Mutex<Totals>>,
+    ) -> Result<()> {
+        let paths: Vec<_> = walk_roots.iter().map(|(path, _)| path.clone()).collect();
+        let mut visitor_builder = MtimeVisitorBuilder::new(self, walk_roots, commit_times, totals.clone());
+
+        self.walk_builder(&paths)?
+            .max_depth(max_depth)
+            .build_parallel()
+            .visit(&mut visitor_builder);
+        Ok(())
+    }
+
+    // Scan the directories that haven't changed using their listings from the
+    // cache, then walk the ones that have, and save all the listings for next
+    // time if the scan was complete.
+    fn scan_cached(&self, cache_path: &Path, commit_times: Option<&CommitTimes>, totals: &Arc<Mutex<Totals>>) -> Result<()> {
+        let scan_start = SystemTime::now();
+        let key = self.cache_key()?;
+        let mut old_cache = if self.parents { Cache::load(cache_path, &key) } else { None };
+        let mut new_cache = Cache::new(key);
+
+        // Changed directories, with the index of the scanned path they are
+        // under and their depth below it.
+        let mut dirty = Vec::new();
+        match &mut old_cache {
+            Some(old_cache) => {
+                let roots = self.paths.iter().cloned().enumerate().map(|(root, path)| (path, root, 0)).collect();
+                let queue = CachedQueue::new(roots);
+                let threads = std::thread::available_parallelism().map_or(1, NonZeroUsize::get);
+                let listings: Vec<CachedListings> = std::thread::scope(|scope| {
+                    let handles: Vec<_> = (0..threads)
+                        .map(|_| scope.spawn(|| self.visit_cached_dirs(&*old_cache, &queue, commit_times, totals)))
+                        .collect();
+                    handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+                });
+                if queue.state.lock().unwrap().quit {
+                    return Ok(());
+                }
+                for listings in listings {
+                    for dir in listings.unchanged {
+                        if let Some(record) = old_cache.remove(&dir) {
+                            new_cache.insert(dir, record);
+                        }
+                    }
+                    dirty.extend(listings.dirty);
+                }
+            }
+            None => dirty.extend(self.paths.iter().cloned().enumerate().map(|(root, path)| (path, root, 0))),
+        }
+
+        // Walks are grouped by depth so the max depth is still relative to
+        // the scanned paths.
+        let mut walks: BTreeMap<Option<usize>, Vec<(PathBuf, usize)>> = BTreeMap::new();
+        for (path, root, depth) in dirty {
+            let max_depth = self.max_depth.map(|max_depth| max_depth.saturating_sub(depth));
+            walks.entry(max_depth).or_default().push((path, root));
+        }
+        for (max_depth, walk_roots) in &walks {
+            self.walk(walk_roots, *max_depth, commit_times, totals)?;
+        }
+
+        let mut totals = totals.lock().unwrap();
+        if totals.quit || !totals.errors.is_empty() {
+            return Ok(());
+        }
+        let mut records: HashMap<PathBuf, DirRecord> = std::mem::take(&mut totals.dir_stats)
+            .into_iter()
+            .filter(|(_, stat)| !stat.is_racy(scan_start, self.racy_window))
+            .map(|(dir, stat)| (dir, DirRecord { stat, children: Vec::new() }))
+            .collect();
+        for (dir, child) in std::mem::take(&mut totals.dir_children) {
+            if let Some(record) = records.get_mut(&dir) {
+                record.children.push(child);
+            }
+        }
+        // Rewriting the cache when nothing changed would change the times of
+        // its directory, which is often scanned too.
+        if records.is_empty() && old_cache.as_ref().is_some_and(Cache::is_empty) {
+            return Ok(());
+        }
+        for (dir, mut record) in records {
+            // Sorted so the cache file doesn't depend on thread timing.
+            record.children.sort_by(|a, b| a.name.cmp(&b.name));
+            new_cache.insert(dir, record);
+        }
+        new_cache.save(cache_path)
+    }
+
+    // One thread's share of visiting directories using their listings from
+    // the cache.
+    fn visit_cached_dirs(
+        &self,
+        cache: &Cache,
+        queue: &CachedQueue,
+        commit_times: Option<&CommitTimes>,
+        totals: &Arc<Mutex<Totals>>,
+    ) -> CachedListings {
+        let mut visitor = MtimeVisitor::new(self, &[], commit_times, totals.clone());
+        let mut listings = CachedListings::default();
+        while let Some((dir, root, depth)) = queue.pop() {
+            let state = visitor.visit_cached(cache, &dir, root, depth, &mut listings);
+            queue.done(listings.subdirs.drain(..), state);
+        }
+        listings
+    }
+
+    // Everything that affects which entries are found, so that a cache written
+    // with different options isn't used.
+    fn cache_key(&self) -> Result<String> {
+        let current_dir = std::env::current_dir().context("error getting current directory")?;
+        let options = format!(
+            "{:?}",
+            (
+                &current_dir,
+                &self.paths,
+                &self.overrides,
+                &self.ignore_file_names,
+                &self.ignore_files,
+                [self.hidden, self.parents, self.ignore, self.git_ignore, self.git_global, self.git_exclude],
+                [self.require_git, self.same_file_system],
+                self.max_depth,
+            )
+        );
+        let mut hasher = Sha256::new();
+        hasher.update(options.as_bytes());
+        // Ignore files that aren't in the scanned directories.
+        hasher.update(cache::files_digest(&self.ignore_files).as_bytes());
+        for path in &self.paths {
+            for parent in current_dir.join(path).ancestors().skip(1) {
+                hasher.update(cache::ignore_files_digest(parent, &self.ignore_file_names).as_bytes());
+            }
+        }
+        Ok(hash::hex(&hasher.finalize()))
+    }
+}
+
+/// The result of a scan.
+#[derive(Debug)]
+pub struct ScanResult {
+    /// Maximum time of all the entries visited, or `UNIX_EPOCH` if there were
+    /// none. This is the mtime unless `Scanner::time_kind()` was used.
+    pub max_time: SystemTime,
+    /// Path of the entry with the maximum time, as walked (i.e. starting with
+    /// the scanned path). If several entries share the maximum time this is
+    /// the one that sorts first.
+    pub max_path: Option<PathBuf>,
+    /// The newest entries if requested with `Scanner::top()`, newest first.
+    /// Entries with the same time are sorted by path.
+    pub top: Vec<(PathBuf, SystemTime)>,
+    /// Number of entries (files, directories, etc.) visited, not counting
+    /// those skipped by `Scanner::entry_filter()`.
+    pub entries: u64,
+    /// Fingerprint of the entries if requested with `Scanner::fingerprint()`,
+    /// as 32 hex digits. It doesn't depend on the order the entries were
+    /// visited in, and paths in it are relative to the scanned paths so it
+    /// doesn't change if the whole tree is moved.
+    pub fingerprint: Option<String>,
+    /// Content hash of the entries if requested with `Scanner::hash()`, as
+    /// 32 hex digits. Like the fingerprint it doesn't depend on the visiting
+    /// order or where the tree is, but it only covers paths and contents.
+    pub hash: Option<String>,
+    /// Number of entries that disappeared during the walk and were skipped.
+    /// See `Scanner::strict()`.
+    pub vanished: u64,
+    /// Number of directories whose listings came from the cache, if one was
+    /// used with `Scanner::cache()`.
+    pub cached_dirs: u64,
+    /// Paths of the directories scanned, as walked, if requested with
+    /// `Scanner::collect_dirs()`. Sorted.
+    pub dirs: Vec<PathBuf>,
+    /// The entries visited, if requested with `Scanner::collect_entries()`.
+    /// Sorted by path.
+    pub entry_list: Vec<Entry>,
+    /// Results for each of the scanned paths, in the order they were added.
+    pub roots: Vec<RootResult>,
+    /// Errors encountered during the walk, sorted by path. Unless
+    /// `Scanner::keep_going()` was used the walk stops at the first error, but
+    /// several threads may have failed before they noticed.
+    pub errors: Vec<ScanError>,
+}
+
+impl ScanResult {
+    /// Fail with the first error if there were any.
+    pub fn into_result(mut self) -> Result<Self> {
+        if self.errors.is_empty() {
+            Ok(self)
+        } else {
+            Err(self.errors.swap_remove(0).error)
+        }
+    }
+
+    /// Encode the result as a single line of JSON. Times are given both as
+    /// integer nanoseconds since the Unix epoch and as RFC 3339 strings in UTC,
+    /// and paths are as walked.
+    pub fn to_json(&self) -> Result<String> {
+        let roots = self
+            .roots
+            .iter()
+            .map(|root| {
+                Ok(json::object([
+                    ("path", json::path(&root.path)),
+                    ("max_time_nanos", json::nanos(root.max_time)),
+                    ("max_time", json::rfc3339(root.max_time)?),
+                    ("max_path", json::opt_path(root.max_path.as_deref())),
+                    ("entries", root.entries.to_string()),
+                ]))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let top = self
+            .top
+            .iter()
+            .map(|(path, time)| {
+                Ok(json::object([
+                    ("path", json::path(path)),
+                    ("time_nanos", json::nanos(*time)),
+                    ("time", json::rfc3339(*time)?),
+                ]))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let errors = self.errors.iter().map(|e| json::string(&e.to_string()));
+
+        Ok(json::object([
+            ("version", JSON_VERSION.to_string()),
+            ("max_time_nanos", json::nanos(self.max_time)),
+            ("max_time", json::rfc3339(self.max_time)?),
+            ("max_path", json::opt_path(self.max_path.as_deref())),
+            ("entries", self.entries.to_string()),
+            ("fingerprint", json::opt_string(self.fingerprint.as_deref())),
+            ("hash", json::opt_string(self.hash.as_deref())),
+            ("vanished", self.vanished.to_string()),
+            ("cached_dirs", self.cached_dirs.to_string()),
+            ("roots", json::array(roots)),
+            ("top", json::array(top)),
+            ("errors", json::array(errors)),
+        ]))
+    }
+}
+
+/// An entry visited by the scan. See `Scanner::collect_entries()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    /// Path as walked, i.e. starting with the scanned path.
+    pub path: PathBuf,
+    /// Index of the scanned path it was found under, in the order they were
+    /// added.
+    pub root: usize,
+    pub file_type: std::fs::FileType,
+    /// Size in bytes.
+    pub size: u64,
+    /// The time used for the maximum; see `Scanner::time_kind()`.
+    pub time: SystemTime,
+    /// SHA-256 of the contents of files and the targets of symlinks, as 64 hex
+    /// digits, if requested with `Scanner::hash_entries()`.
+    pub hash: Option<String>,
+}
+
+/// An error for part of the walk.
+#[derive(Debug)]
+pub struct ScanError {
+    /// The path the error relates to, if known.
+    pub path: Option<PathBuf>,
+    /// The error, including its causes. The message includes the path.
+    pub error: anyhow::Error,
+}
+
+impl fmt::Display for ScanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#}", self.error)
+    }
+}
+
+/// The result for one of the scanned paths.
+#[derive(Debug)]
+pub struct RootResult {
+    /// The scanned path.
+    pub path: PathBuf,
+    /// Maximum time of the entries under this path.
+    pub max_time: SystemTime,
+    /// Path of the entry with the maximum time.
+    pub max_path: Option<PathBuf>,
+    /// Number of entries visited under this path.
+    pub entries: u64,
+}
+
+impl RootResult {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            max_time: UNIX_EPOCH,
+            max_path: None,
+            entries: 0,
+        }
+    }
+
+    // True if an entry at `path` with the given time should replace the
+    // current maximum. Ties are broken on the path so the result doesn't
+    // depend on the order the threads visited things in.
+    fn is_new_max(&self, time: SystemTime, path: &Path) -> bool {
+        time > self.max_time
+            || (time == self.max_time && self.max_path.as_deref().is_none_or(|max_path| path < max_path))
+    }
+
+    fn add(&mut self, time: SystemTime, path: &Path) {
+        if self.is_new_max(time, path) {
+            self.max_time = time;
+            self.max_path = Some(path.to_owned());
+        }
+        self.entries += 1;
+    }
+
+    fn merge(&mut self, other: &RootResult) {
+        if let Some(other_path) = &other.max_path {
+            if self.is_new_max(other.max_time, other_path) {
+                self.max_time = other.max_time;
+                self.max_path = Some(other_path.clone());
+            }
+        }
+        self.entries += other.entries;
+    }
+}
+
+// Results for a single thread, or merged from all threads.
+struct Totals {
+    // Indexed in the same order as `Scanner::paths`.
+    roots: Vec<RootResult>,
+    // Min-heap of the newest entries, so the oldest can be evicted when it
+    // exceeds `top_limit`.
+    top: BinaryHeap<Reverse<TopKey>>,
+    top_limit: usize,
+    fingerprint: SetDigest,
+    hash: SetDigest,
+    vanished: u64,
+    errors: Vec<ScanError>,
+    // True if the walk was stopped by `Scanner::quit_if_newer_than()`.
+    quit: bool,
+    cached_dirs: u64,
+    dirs: Vec<PathBuf>,
+    entry_list: Vec<Entry>,
+    // Directories walked and their entries, for the cache.
+    dir_stats: Vec<(PathBuf, DirStat)>,
+    dir_children: Vec<(PathBuf, Child)>,
+}
+
+// Sort key for the newest entries. Greater is newer, and equal times are
+// ordered so that the path that sorts first is "newer".
+type TopKey = (SystemTime, Reverse<PathBuf>);
+
+impl Totals {
+    fn new(scanner: &Scanner) -> Self {
+        Self {
+            roots: scanner.paths.iter().cloned().map(RootResult::new).collect(),
+            top: BinaryHeap::new(),
+            top_limit: scanner.top,
+            fingerprint: SetDigest::default(),
+            hash: SetDigest::default(),
+            vanished: 0,
+            errors: Vec::new(),
+            quit: false,
+            cached_dirs: 0,
+            dirs: Vec::new(),
+            entry_list: Vec::new(),
+            dir_stats: Vec::new(),
+            dir_children: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, root: usize, time: SystemTime, path: &Path) {
+        self.roots[root].add(time, path);
+        if self.top_limit > 0 && !self.is_below_top(time, path) {
+            self.push_top((time, Reverse(path.to_owned())));
+        }
+    }
+
+    // True if the top list is full and an entry at `path` with the given time
+    // would be evicted immediately. Saves copying the path for most entries.
+    fn is_below_top(&self, time: SystemTime, path: &Path) -> bool {
+        match self.top.peek() {
+            Some(Reverse((oldest_time, Reverse(oldest_path)))) if self.top.len() >= self.top_limit => {
+                time < *oldest_time || (time == *oldest_time && path > oldest_path.as_path())
+            }
+            _ => false,
+        }
+    }
+
+    fn push_top(&mut self, key: TopKey) {
+        self.top.push(Reverse(key));
+        if self.top.len() > self.top_limit {
+            self.top.pop();
+        }
+    }
+
+    fn merge(&mut self, other: Totals) {
+        for (root, other_root) in self.roots.iter_mut().zip(&other.roots) {
+            root.merge(other_root);
+        }
+        for Reverse(key) in other.top {
+            self.push_top(key);
+        }
+        self.fingerprint.merge(other.fingerprint);
+        self.hash.merge(other.hash);
+        self.vanished += other.vanished;
+        self.errors.extend(other.errors);
+        self.quit |= other.quit;
+        self.cached_dirs += other.cached_dirs;
+        self.dirs.extend(other.dirs);
+        self.entry_list.extend(other.entry_list);
+        self.dir_stats.extend(other.dir_stats);
+        self.dir_children.extend(other.dir_children);
+    }
+}
+
+struct MtimeVisitor<'s> {
+    scanner: &'s Scanner,
+    // The paths being walked, paired with the index of the scanned path they
+    // are under. They're only different when using the cache.
+    walk_roots: &'s [(PathBuf, usize)],
+    commit_times: Option<&'s CommitTimes>,
+    // Results for this thread.
+    thread_totals: Totals,
+    // Results for all threads.
+    totals: Arc<Mutex<Totals>>,
+}
+
+impl<'s> MtimeVisitor<'s> {
+    fn new(
+        scanner: &'s Scanner,
+        walk_roots: &'s [(PathBuf, usize)],
+        commit_times: Option<&'s CommitTimes>,
+        totals: Arc<Mutex<Totals>>,
+    ) -> Self {
+        Self {
+            scanner,
+            walk_roots,
+            commit_times,
+            thread_totals: Totals::new(scanner),
+            totals,
+        }
+    }
+}
+
+impl Drop for MtimeVisitor<'_> {
+    fn drop(&mut self) {
+        let thread_totals = std::mem::replace(&mut self.thread_totals, Totals::new(self.scanner));
+        self.totals.lock().unwrap().merge(thread_totals);
+    }
+}
+
+impl MtimeVisitor<'_> {
+    fn visit_inner(&mut self, entry: &ignore::DirEntry) -> Result<ignore::WalkState> {
+        let recording = self.scanner.cache.is_some();
+        let is_dir = entry.file_type().is_some_and(|file_type| file_type.is_dir());
+        if self.scanner.collect_dirs && is_dir {
+            self.thread_totals.dirs.push(entry.path().to_owned());
+        }
+        if recording && entry.depth() > 0 {
+            if let (Some(dir), Some(name)) = (entry.path().parent(), entry.path().file_name()) {
+                let child = Child {
+                    name: name.to_owned(),
+                    is_dir,
+                };
+                self.thread_totals.dir_children.push((dir.to_owned(), child));
+            }
+        }
+
+        // The file type comes from the directory listing so this saves a stat,
+        // except that the cache needs the times of all directories.
+        let matches = self.scanner.counts(entry.file_type());
+        let needs_metadata = matches || (recording && is_dir);
+        if !needs_metadata {
+            return Ok(ignore::WalkState::Continue);
+        }
+        let metadata = match entry.metadata() {
+            Err(e) if self.is_vanished(e.io_error()) => return Ok(ignore::WalkState::Continue),
+            metadata => metadata.with_context(|| anyhow!("error reading metadata for path {}", entry.path().display()))?,
+        };
+        if recording && is_dir {
+            if let Some(stat) = DirStat::new(entry.path(), &metadata, &self.scanner.ignore_file_names) {
+                self.thread_totals.dir_stats.push((entry.path().to_owned(), stat));
+            }
+        }
+        if !matches {
+            return Ok(ignore::WalkState::Continue);
+        }
+        self.add_entry(self.root_index(entry), entry.path(), &metadata)
+    }
+
+    // Add an entry that passed the filters to the results.
+    fn add_entry(&mut self, root: usize, path: &Path, metadata: &std::fs::Metadata) -> Result<ignore::WalkState> {
+        let time_kind = self.scanner.time_kind;
+        let commit_time = self.commit_times.and_then(|times| times.get(root, self.relative_path(root, path)));
+        let time = match commit_time {
+            Some(time) => time,
+            None => time_kind.get(metadata).with_context(|| anyhow!("error getting {} for path {}", time_kind, path.display()))?,
+        };
+        self.thread_totals.add(root, time, path);
+        if self.scanner.collect_entries && self.scanner.collect_newer_than.is_none_or(|threshold| time > threshold) {
+            let mut hash = None;
+            if self.scanner.hash_entries {
+                let mut hasher = Sha256::new();
+                match hash_contents(&mut hasher, path, metadata.file_type()) {
+                    Ok(()) => hash = Some(hash::hex(&hasher.finalize())),
+                    Err(e) if self.is_vanished(Some(&e)) => return Ok(ignore::WalkState::Continue),
+                    Err(e) => return Err(e).with_context(|| anyhow!("error reading contents of {}", path.display())),
+                }
+            }
+            self.thread_totals.entry_list.push(Entry {
+                path: path.to_owned(),
+                root,
+                file_type: metadata.file_type(),
+                size: metadata.len(),
+                time,
+                hash,
+            });
+        }
+        if self.scanner.fingerprint {
+            let hash = entry_fingerprint(root, self.relative_path(root, path), metadata, time);
+            self.thread_totals.fingerprint.add(hash);
+        }
+        if self.scanner.hash {
+            match entry_content_hash(root, self.relative_path(root, path), path, metadata.file_type()) {
+                Ok(hash) => self.thread_totals.hash.add(hash),
+                Err(e) if self.is_vanished(Some(&e)) => {}
+                Err(e) => return Err(e).with_context(|| anyhow!("error reading contents of {}", path.display())),
+            }
+        }
+        if self.scanner.quit_if_newer_than.is_some_and(|threshold| time > threshold) {
+            self.thread_totals.quit = true;
+            return Ok(ignore::WalkState::Quit);
+        }
+        Ok(ignore::WalkState::Continue)
+    }
+
+    // Visit a directory using its listing from the cache if it hasn't changed,
+    // and then the files in it. Its subdirectories are added to
+    // `listings.subdirs` to be visited next, and if it isn't in the cache or
+    // has changed it is added to `listings.dirty` to be walked later instead.
+    fn visit_cached(
+        &mut self,
+        cache: &Cache,
+        dir: &Path,
+        root: usize,
+        depth: usize,
+        listings: &mut CachedListings,
+    ) -> ignore::WalkState {
+        let metadata = match self.cached_metadata(dir) {
+            Ok(Some(metadata)) => metadata,
+            Ok(None) => return ignore::WalkState::Continue,
+            Err(e) => return self.handle_error(e),
+        };
+        let record = DirStat::new(dir, &metadata, &self.scanner.ignore_file_names)
+            .filter(|_| metadata.is_dir())
+            .and_then(|stat| cache.get(dir, &stat));
+        let Some(record) = record else {
+            listings.dirty.push((dir.to_owned(), root, depth));
+            return ignore::WalkState::Continue;
+        };
+        listings.unchanged.push(dir.to_owned());
+        self.thread_totals.cached_dirs += 1;
+        if self.scanner.collect_dirs {
+            self.thread_totals.dirs.push(dir.to_owned());
+        }
+
+        if self.scanner.counts(Some(metadata.file_type())) {
+            let state = self.add_cached_entry(root, dir, &metadata);
+            if state == ignore::WalkState::Quit {
+                return state;
+            }
+        }
+        for child in &record.children {
+            let path = dir.join(&child.name);
+            let state = if child.is_dir {
+                listings.subdirs.push((path, root, depth + 1));
+                ignore::WalkState::Continue
+            } else if self.scanner.entry_filter == EntryFilter::Dirs {
+                ignore::WalkState::Continue
+            } else {
+                match self.cached_metadata(&path) {
+                    Ok(Some(metadata)) => self.add_cached_entry(root, &path, &metadata),
+                    Ok(None) => ignore::WalkState::Continue,
+                    Err(e) => self.handle_error(e),
+                }
+            };
+            if state == ignore::WalkState::Quit {
+                return state;
+            }
+        }
+        ignore::WalkState::Continue
+    }
+
+    // Metadata for an entry listed in the cache, or `None` if it has vanished.
+    fn cached_metadata(&mut self, path: &Path) -> std::result::Result<Option<std::fs::Metadata>, ScanError> {
+        match std::fs::symlink_metadata(path) {
+            Ok(metadata) => Ok(Some(metadata)),
+            Err(e) if self.is_vanished(Some(&e)) => Ok(None),
+            Err(e) => Err(ScanError {
+                path: Some(path.to_owned()),
+                error: anyhow::Error::new(e).context(format!("error reading metadata for path {}", path.display())),
+            }),
+        }
+    }
+
+    fn add_cached_entry(&mut self, root: usize, path: &Path, metadata: &std::fs::Metadata) -> ignore::WalkState {
+        self.add_entry(root, path, metadata).unwrap_or_else(|error| {
+            self.handle_error(ScanError {
+                path: Some(path.to_owned()),
+                error,
+            })
+        })
+    }
+
+    // Record an error and decide whether to carry on.
+    fn handle_error(&mut self, error: ScanError) -> ignore::WalkState {
+        self.thread_totals.errors.push(error);
+        if self.scanner.keep_going {
+            ignore::WalkState::Continue
+        } else {
+            ignore::WalkState::Quit
+        }
+    }
+
+    // True if the error is because something was deleted during the walk, and
+    // it should be skipped. Counts it if so.
+    fn is_vanished(&mut self, error: Option<&std::io::Error>) -> bool {
+        let vanished = !self.scanner.strict && error.map(std::io::Error::kind) == Some(std::io::ErrorKind::NotFound);
+        if vanished {
+            self.thread_totals.vanished += 1;
+        }
+        vanished
+    }
+
+    // Index of the scanned path that the entry was found under. Entries are
+    // always the walked path joined with `depth()` components.
+    fn root_index(&self, entry: &ignore::DirEntry) -> usize {
+        let walk_root = entry.path().ancestors().nth(entry.depth());
+        self.walk_roots
+            .iter()
+            .find(|(path, _)| Some(path.as_path()) == walk_root)
+            .map_or(0, |(_, root)| *root)
+    }
+
+    // Path of an entry relative to the scanned path it was found under.
+    fn relative_path<'p>(&self, root: usize, path: &'p Path) -> &'p Path {
+        path.strip_prefix(&self.scanner.paths[root]).unwrap_or(path)
+    }
+}
+
+// Start hashing an entry with what identifies it: the scanned path it was
+// found under, its type and its relative path. Paths are hashed with `/`
+// separators so hashes are the same on all platforms.
+fn entry_hasher(root: usize, relative_path: &Path, file_type: std::fs::FileType) -> Sha256 {
+    let type_byte = if file_type.is_dir() {
+        b'd'
+    } else if file_type.is_file() {
+        b'f'
+    } else if file_type.is_symlink() {
+        b'l'
+    } else {
+        b'o'
+    };
+    let path: Vec<_> = relative_path.components().map(|c| c.as_os_str().to_string_lossy()).collect();
+    let path = path.join("/");
+
+    let mut hasher = Sha256::new();
+    hasher.update((root as u64).to_le_bytes());
+    hasher.update([type_byte]);
+    hasher.update((path.len() as u64).to_le_bytes());
+    hasher.update(path.as_bytes());
+    hasher
+}
+
+// Hash of everything the fingerprint covers for one entry.
+fn entry_fingerprint(root: usize, relative_path: &Path, metadata: &std::fs::Metadata, time: SystemTime) -> [u8; 32] {
+    let mut hasher = entry_hasher(root, relative_path, metadata.file_type());
+    hasher.update(metadata.len().to_le_bytes());
+    hasher.update(time::OffsetDateTime::from(time).unix_timestamp_nanos().to_le_bytes());
+    hasher.finalize().into()
+}
+
+// Hash of an entry's path and contents.
+fn entry_content_hash(root: usize, relative_path: &Path, path: &Path, file_type: std::fs::FileType) -> std::io::Result<[u8; 32]> {
+    let mut hasher = entry_hasher(root, relative_path, file_type);
+    hash_contents(&mut hasher, path, file_type)?;
+    Ok(hasher.finalize().into())
+}
+
+// Hash an entry's contents: the contents of files and the targets of
+// symlinks. Other entries have nothing to hash.
+fn hash_contents(hasher: &mut Sha256, path: &Path, file_type: std::fs::FileType) -> std::io::Result<()> {
+    if file_type.is_file() {
+        let mut file = std::fs::File::open(path)?;
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            let n = match file.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            hasher.update(&buffer[..n]);
+        }
+    } else if file_type.is_symlink() {
+        hasher.update(std::fs::read_link(path)?.to_string_lossy().as_bytes());
+    }
+    Ok(())
+}
+
+impl ignore::ParallelVisitor for MtimeVisitor<'_> {
+    fn visit(
+        &mut self,
+        entry: std::result::Result<ignore::DirEntry, ignore::Error>,
+    ) -> ignore::WalkState {
+        let result = match entry {
+            Ok(entry) => self.visit_inner(&entry).map_err(|error| ScanError {
+                path: Some(entry.into_path()),
+                error,
+            }),
+            Err(e) if self.is_vanished(e.io_error()) => Ok(ignore::WalkState::Continue),
+            Err(e) => Err(ScanError {
+                path: error_path(&e),
+                error: anyhow::Error::new(e).context("error reading directory entry"),
+            }),
+        };
+
+        result.unwrap_or_else(|error| self.handle_error(error))
+    }
+}
+
+// The path that a walk error relates to, if it has one.
+fn error_path(error: &ignore::Error) -> Option<PathBuf> {
+    match error {
+        ignore::Error::WithPath { path, .. } => Some(path.clone()),
+        ignore::Error::WithDepth { err, .. } | ignore::Error::WithLineNumber { err, .. } => error_path(err),
+        ignore::Error::Partial(errs) => errs.iter().find_map(error_path),
+        ignore::Error::Loop { child, .. } => Some(child.clone()),
+        _ => None,
+    }
+}
+
+// What visiting directories using their listings from the cache found. Each
+// directory is the path, the index of the scanned path it is under and its
+// depth below it.
+#[derive(Default)]
+struct CachedListings {
+    // Directories that haven't changed, whose listings are kept in the new
+    // cache.
+    unchanged: Vec<PathBuf>,
+    // Subdirectories of those, to visit next.
+    subdirs: Vec<(PathBuf, usize, usize)>,
+    // Directories that aren't in the cache or have changed, to be walked.
+    dirty: Vec<(PathBuf, usize, usize)>,
+}
+
+// Directories waiting to be visited using their listings from the cache,
+// shared by the threads visiting them.
+struct CachedQueue {
+    state: Mutex<CachedQueueState>,
+    // Notified when directories are added or a thread finishes one.
+    changed: Condvar,
+}
+
+struct CachedQueueState {
+    dirs: Vec<(PathBuf, usize, usize)>,
+    // Threads visiting a directory, which may add more.
+    busy: usize,
+    // True if a thread stopped the scan.
+    quit: bool,
+}
+
+impl CachedQueue {
+    fn new(dirs: Vec<(PathBuf, usize, usize)>) -> Self {
+        Self {
+            state: Mutex::new(CachedQueueState {
+                dirs,
+                busy: 0,
+                quit: false,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    // The next directory to visit, waiting for other threads to add more if
+    // there are none, or `None` once they are all done or the scan stopped.
+    fn pop(&self) -> Option<(PathBuf, usize, usize)> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.quit {
+                return None;
+            }
+            if let Some(dir) = state.dirs.pop() {
+                state.busy += 1;
+                return Some(dir);
+            }
+            if state.busy == 0 {
+                return None;
+            }
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+
+    // Finish visiting a directory, queueing its subdirectories.
+    fn done(&self, subdirs: impl Iterator<Item = (PathBuf, usize, usize)>, walk_state: ignore::WalkState) {
+        let mut state = self.state.lock().unwrap();
+        state.dirs.extend(subdirs);
+        state.busy -= 1;
+        state.quit |= walk_state == ignore::WalkState::Quit;
+        self.changed.notify_all();
+    }
+}
+
+struct MtimeVisitorBuilder<'s> {
+    scanner: &'s Scanner,
+    walk_roots: &'s [(PathBuf, usize)],
+    commit_times: Option<&'s CommitTimes>,
+    // Results for all threads.
+    totals: Arc<Mutex<Totals>>,
+}
+
+impl<'s> MtimeVisitorBuilder<'s> {
+    fn new(
+        scanner: &'s Scanner,
+        walk_roots: &'s [(PathBuf, usize)],
+        commit_times: Option<&'s CommitTimes>,
+        totals: Arc<Mutex<Totals>>,
+    ) -> Self {
+        Self {
+            scanner,
+            walk_roots,
+            commit_times,
+            totals,
+        }
+    }
+}
+
+impl<'s> ignore::ParallelVisitorBuilder<'s> for MtimeVisitorBuilder<'s> {
+    fn build(&mut self) -> Box<dyn ignore::ParallelVisitor + 's> {
+        Box::new(MtimeVisitor::new(self.scanner, self.walk_roots, self.commit_times, self.totals.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::distributions::Alphanumeric;
+    use rand::Rng;
+
+    fn rand_string(n: usize) -> String {
+        let mut rng = rand::thread_rng();
+        (0..n).map(|_| rng.sample(Alphanumeric) as char).collect()
+    }
+
+    fn set_mtime(path: &Path, max_mtime: &mut SystemTime) {
+        let mut rng = rand::thread_rng();
+        let mtime = UNIX_EPOCH + std::time::Duration::from_secs(rng.gen_range(0..100));
+        filetime::set_file_mtime(path, filetime::FileTime::from_system_time(mtime)).unwrap();
+        *max_mtime = mtime.max(*max_mtime);
+    }
+
+    // Create the files, and the directories they are in, setting their mtimes
+    // in seconds in order so a directory can come after its entries. The
+    // mtime of `root` is reset to 0 since creating them changed it.
+    fn make_tree(root: &Path, entries: &[(&str, u64)]) {
+        for &(name, secs) in entries {
+            let path = root.join(name);
+            if !path.exists() {
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(&path, name).unwrap();
+            }
+            let mtime = UNIX_EPOCH + std::time::Duration::from_secs(secs);
+            filetime::set_file_mtime(&path, filetime::FileTime::from_system_time(mtime)).unwrap();
+        }
+        filetime::set_file_mtime(root, filetime::FileTime::from_system_time(UNIX_EPOCH)).unwrap();
+    }
+
+    // The paths of all the entries a scan finds, relative to `root`.
+    fn scanned(scanner: &mut Scanner, root: &Path) -> Vec<String> {
+        let result = scanner.top(usize::MAX).scan().unwrap().into_result().unwrap();
+        let mut paths: Vec<_> = result
+            .top
+            .iter()
+            .map(|(path, _)| path.strip_prefix(root).unwrap().to_str().unwrap().to_owned())
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn test_mtime() {
+        fn make_rand_dir(path: &Path, max_levels: usize, max_mtime: &mut SystemTime, entries: &mut u64) {
+            if max_levels == 0 {
+                return;
+            }
+            // Create this directory.
+            let dir = path.join(rand_string(10));
+            std::fs::create_dir(&dir).unwrap();
+            *entries += 1;
+
+            let mut rng = rand::thread_rng();
+
+            // Create some directories.
+            for _ in 0..rng.gen_range(0..3) {
+                make_rand_dir(&dir, max_levels - 1, max_mtime, entries);
+            }
+            // Create some files in this directory.
+            for _ in 0..rng.gen_range(0..20) {
+                let file_name = dir.join(rand_string(8));
+                std::fs::write(&file_name, rand_string(100)).unwrap();
+                *entries += 1;
+                // Set the mtime for this file.
+                set_mtime(&file_name, max_mtime);
+            }
+            // Set the mtime for this directory.
+            set_mtime(&dir, max_mtime);
+        }
+
+        // Create temporary directory.
+        let temp_dir = tempfile::tempdir().unwrap();
+        let temp_dir_path = temp_dir.path();
+
+        // Create random directory structure with random mtimes.
+        let root = temp_dir_path.join("root");
+        std::fs::create_dir(&root).unwrap();
+
+        // Max mtime for all files and directories.
+        let mut max_mtime = UNIX_EPOCH;
+        // The root counts as an entry too.
+        let mut entries = 1;
+
+        make_rand_dir(&root, 5, &mut max_mtime, &mut entries);
+
+        set_mtime(&root, &mut max_mtime);
+
+        let result = Scanner::new(&root).scan().unwrap().into_result().unwrap();
+
+        assert_eq!(result.max_time, max_mtime);
+        assert_eq!(result.entries, entries);
+    }
+
+    #[test]
+    fn test_max_path() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+
+        let mtime = UNIX_EPOCH + std::time::Duration::from_secs(50);
+        for (name, secs) in [("a", 10), ("c", 50), ("b", 50), ("d", 20)] {
+            let path = root.join(name);
+            std::fs::write(&path, name).unwrap();
+            let mtime = UNIX_EPOCH + std::time::Duration::from_secs(secs);
+            filetime::set_file_mtime(&path, filetime::FileTime::from_system_time(mtime)).unwrap();
+        }
+        filetime::set_file_mtime(root, filetime::FileTime::from_system_time(UNIX_EPOCH)).unwrap();
+
+        let result = Scanner::new(root).scan().unwrap().into_result().unwrap();
+
+        assert_eq!(result.max_time, mtime);
+        // "b" and "c" tie, so the first in sort order wins.
+        assert_eq!(result.max_path, Some(root.join("b")));
+    }
+
+    #[test]
+    fn test_top() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+
+        make_tree(root, &[("a", 10), ("c", 50), ("b", 50), ("dir/d", 20), ("dir/e", 30), ("dir", 40)]);
+
+        let result = Scanner::new(root).top(4).scan().unwrap().into_result().unwrap();
+
+        let top: Vec<_> = result
+            .top
+            .iter()
+            .map(|(path, time)| (path.strip_prefix(root).unwrap().to_str().unwrap(), time.duration_since(UNIX_EPOCH).unwrap().as_secs()))
+            .collect();
+        assert_eq!(top, [("b", 50), ("c", 50), ("dir", 40), ("dir/e", 30)]);
+        assert_eq!(result.entries, 7);
+    }
+
+    #[test]
+    fn test_entry_filter() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+
+        make_tree(root, &[("a", 10), ("dir/b", 20), ("dir", 40)]);
+
+        let result = Scanner::new(root).entry_filter(EntryFilter::Files).scan().unwrap().into_result().unwrap();
+        assert_eq!(result.max_path, Some(root.join("dir/b")));
+        assert_eq!(result.entries, 2);
+
+        let result = Scanner::new(root).entry_filter(EntryFilter::Dirs).scan().unwrap().into_result().unwrap();
+        assert_eq!(result.max_path, Some(root.join("dir")));
+        assert_eq!(result.entries, 2);
+    }
+
+    #[test]
+    fn test_collect_newer_than() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+
+        make_tree(root, &[("a", 10), ("dir/b", 20), ("dir/c", 30), ("dir", 40)]);
+
+        let paths = |scanner: &mut Scanner| -> Vec<PathBuf> {
+            let result = scanner.collect_entries(true).scan().unwrap().into_result().unwrap();
+            result.entry_list.into_iter().map(|entry| entry.path).collect()
+        };
+        let newer_than = |secs| UNIX_EPOCH + std::time::Duration::from_secs(secs);
+        assert_eq!(paths(&mut Scanner::new(root)).len(), 5);
+        // Only strictly newer entries.
+        assert_eq!(
+            paths(Scanner::new(root).collect_newer_than(newer_than(20))),
+            [root.join("dir"), root.join("dir/c")]
+        );
+        assert_eq!(
+            paths(Scanner::new(root).collect_newer_than(newer_than(5)).entry_filter(EntryFilter::Files)),
+            [root.join("a"), root.join("dir/b"), root.join("dir/c")]
+        );
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().join("root");
+        std::fs::create_dir_all(root.join("dir")).unwrap();
+        for name in ["a", "b", "dir/c"] {
+            std::fs::write(root.join(name), name).unwrap();
+            filetime::set_file_mtime(root.join(name), filetime::FileTime::from_unix_time(1, 0)).unwrap();
+        }
+        filetime::set_file_mtime(root.join("dir"), filetime::FileTime::from_unix_time(2, 0)).unwrap();
+
+        let scan = || {
+            Scanner::new(&root)
+                .entry_filter(EntryFilter::Files)
+                .fingerprint(true)
+                .scan()
+                .unwrap()
+                .into_result()
+                .unwrap()
+        };
+
+        let before = scan();
+        let fingerprint = before.fingerprint.clone().unwrap();
+        assert_eq!(fingerprint.len(), 32);
+        assert_eq!(scan().fingerprint.as_ref(), Some(&fingerprint));
+        assert!(Scanner::new(&root).scan().unwrap().fingerprint.is_none());
+
+        // Deleting a file that isn't the newest doesn't change the max time,
+        // but does change the fingerprint.
+        std::fs::remove_file(root.join("b")).unwrap();
+        let after = scan();
+        assert_eq!(after.max_time, before.max_time);
+        assert_ne!(after.fingerprint.as_ref(), Some(&fingerprint));
+
+        // It doesn't depend on where the tree is.
+        std::fs::write(root.join("b"), "b").unwrap();
+        filetime::set_file_mtime(root.join("b"), filetime::FileTime::from_unix_time(1, 0)).unwrap();
+        let moved = temp_dir.path().join("moved");
+        std::fs::rename(&root, &moved).unwrap();
+        let result = Scanner::new(&moved)
+            .entry_filter(EntryFilter::Files)
+            .fingerprint(true)
+            .scan()
+            .unwrap();
+        assert_eq!(result.fingerprint, Some(fingerprint));
+    }
+
+    #[test]
+    fn test_hash() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+        std::fs::create_dir(root.join("dir")).unwrap();
+        std::fs::write(root.join("a"), "a").unwrap();
+        std::fs::write(root.join("dir/b"), "b").unwrap();
+
+        let hash = || Scanner::new(root).hash(true).scan().unwrap().into_result().unwrap().hash.unwrap();
+        let before = hash();
+        assert_eq!(before.len(), 32);
+
+        // Rewriting with the same contents and new mtimes doesn't change it.
+        std::fs::write(root.join("a"), "a").unwrap();
+        for name in ["a", "dir/b", "dir", ""] {
+            filetime::set_file_mtime(root.join(name), filetime::FileTime::from_unix_time(1, 0)).unwrap();
+        }
+        assert_eq!(hash(), before);
+
+        // But changing the contents or renaming does.
+        std::fs::write(root.join("a"), "c").unwrap();
+        assert_ne!(hash(), before);
+        std::fs::write(root.join("a"), "a").unwrap();
+        assert_eq!(hash(), before);
+        std::fs::rename(root.join("dir/b"), root.join("dir/c")).unwrap();
+        assert_ne!(hash(), before);
+    }
+
+    #[test]
+    fn test_cache() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().join("root");
+        let cache_path = temp_dir.path().join("cache");
+        std::fs::create_dir_all(root.join(".git")).unwrap();
+        std::fs::create_dir_all(root.join("dir/sub")).unwrap();
+        std::fs::write(root.join(".gitignore"), "*.log\n").unwrap();
+        let set_mtime = |name: &str, secs| {
+            filetime::set_file_mtime(root.join(name), filetime::FileTime::from_unix_time(secs, 0)).unwrap();
+        };
+        for (name, secs) in [("a", 10), ("ignored.log", 90), ("dir/b", 20), ("dir/sub/c", 30)] {
+            std::fs::write(root.join(name), name).unwrap();
+            set_mtime(name, secs);
+        }
+        for dir in ["", "dir", "dir/sub"] {
+            set_mtime(dir, 1);
+        }
+
+        let scan = |configure: &dyn Fn(&mut Scanner)| {
+            let mut scanner = Scanner::new(&root);
+            scanner.cache(&cache_path).collect_dirs(true).collect_entries(true);
+            scanner.racy_window = Duration::ZERO;
+            configure(&mut scanner);
+            let result = scanner.scan().unwrap().into_result().unwrap();
+
+            // Always the same as without the cache.
+            let mut uncached = Scanner::new(&root);
+            uncached.collect_dirs(true).collect_entries(true);
+            configure(&mut uncached);
+            uncached.cache = None;
+            let expected = uncached.scan().unwrap().into_result().unwrap();
+            assert_eq!(result.max_path, expected.max_path);
+            assert_eq!(result.entries, expected.entries);
+            assert_eq!(result.fingerprint, expected.fingerprint);
+            assert_eq!(result.dirs, expected.dirs);
+            assert_eq!(result.entry_list, expected.entry_list);
+            result.cached_dirs
+        };
+        // Nothing is cached without ctimes.
+        let hits = |cached_dirs| if cfg!(unix) { cached_dirs } else { 0 };
+
+        assert_eq!(scan(&|_| {}), 0);
+        assert_eq!(scan(&|_| {}), hits(3));
+
+        // Modifying a file doesn't change its directory.
+        set_mtime("dir/sub/c", 40);
+        assert_eq!(scan(&|_| {}), hits(3));
+
+        // Adding a file does, but its parent's listing is still used.
+        std::fs::write(root.join("dir/d"), "").unwrap();
+        set_mtime("dir/d", 50);
+        set_mtime("dir", 2);
+        assert_eq!(scan(&|_| {}), hits(1));
+        assert_eq!(scan(&|_| {}), hits(3));
+
+        // Editing an ignore file in place is noticed.
+        std::fs::write(root.join(".gitignore"), "*.txt\n").unwrap();
+        assert_eq!(scan(&|_| {}), 0);
+        assert_eq!(scan(&|_| {}), hits(3));
+
+        // Different options, or an invalid cache, mean a full scan.
+        assert_eq!(scan(&|scanner| {
+            scanner.exclude("a");
+        }), 0);
+        assert_eq!(scan(&|_| {}), 0);
+        std::fs::write(&cache_path, "maxtime-cache 1\n").unwrap();
+        assert_eq!(scan(&|_| {}), 0);
+        assert_eq!(scan(&|scanner| {
+            scanner.entry_filter(EntryFilter::Files).fingerprint(true);
+        }), hits(3));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_cache_in_tree() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+        let cache_path = root.join(".maxtime-cache");
+        std::fs::create_dir(root.join("dir")).unwrap();
+        std::fs::write(root.join("a"), "").unwrap();
+        std::fs::write(root.join("dir/b"), "").unwrap();
+        filetime::set_file_mtime(root.join("dir"), filetime::FileTime::from_unix_time(1, 0)).unwrap();
+
+        let scan = || {
+            let mut scanner = Scanner::new(root);
+            scanner.cache(&cache_path);
+            scanner.racy_window = Duration::ZERO;
+            scanner.scan().unwrap().into_result().unwrap().cached_dirs
+        };
+        assert_eq!(scan(), 0);
+        // Creating the cache file changed the root, but writing it again
+        // doesn't.
+        assert_eq!(scan(), 0);
+        let contents = std::fs::read(&cache_path).unwrap();
+        let metadata = std::fs::metadata(&cache_path).unwrap();
+        assert_eq!(scan(), 2);
+        assert_eq!(scan(), 2);
+
+        // An unchanged cache isn't written again.
+        assert_eq!(std::fs::read(&cache_path).unwrap(), contents);
+        assert_eq!(std::fs::metadata(&cache_path).unwrap().modified().unwrap(), metadata.modified().unwrap());
+
+        // Changes to the directory are still noticed.
+        std::fs::write(root.join("dir/c"), "").unwrap();
+        assert_eq!(scan(), 1);
+        assert_eq!(scan(), 2);
+    }
+
+    #[test]
+    fn test_quit_if_newer_than() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+
+        for i in 0..100 {
+            let path = root.join(format!("{i}"));
+            std::fs::write(&path, "").unwrap();
+            let mtime = UNIX_EPOCH + std::time::Duration::from_secs(i);
+            filetime::set_file_mtime(&path, filetime::FileTime::from_system_time(mtime)).unwrap();
+        }
+        filetime::set_file_mtime(root, filetime::FileTime::from_system_time(UNIX_EPOCH)).unwrap();
+
+        let threshold = UNIX_EPOCH + std::time::Duration::from_secs(50);
+        let result = Scanner::new(root).quit_if_newer_than(threshold).scan().unwrap().into_result().unwrap();
+        assert!(result.max_time > threshold);
+
+        // Nothing is newer than the newest file so the whole tree is scanned.
+        let threshold = UNIX_EPOCH + std::time::Duration::from_secs(99);
+        let result = Scanner::new(root).quit_if_newer_than(threshold).scan().unwrap().into_result().unwrap();
+        assert_eq!(result.max_time, threshold);
+        assert_eq!(result.entries, 101);
+    }
+
+    #[test]
+    fn test_multiple_paths() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+
+        make_tree(root, &[("src/a", 10), ("include/b", 20), ("config", 30), ("ignored", 40), ("src", 0), ("include", 0)]);
+
+        let result = Scanner::new(root.join("src"))
+            .add(root.join("include"))
+            .add(root.join("config"))
+            .scan()
+            .unwrap()
+            .into_result()
+            .unwrap();
+
+        assert_eq!(result.max_time, UNIX_EPOCH + std::time::Duration::from_secs(30));
+        assert_eq!(result.max_path, Some(root.join("config")));
+        assert_eq!(result.entries, 5);
+
+        let roots: Vec<_> = result
+            .roots
+            .iter()
+            .map(|root| (root.max_time.duration_since(UNIX_EPOCH).unwrap().as_secs(), root.entries))
+            .collect();
+        assert_eq!(roots, [(10, 2), (20, 2), (30, 1)]);
+        assert_eq!(result.roots[1].max_path, Some(root.join("include/b")));
+    }
+
+    #[test]
+    fn test_include_exclude() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+
+        std::fs::create_dir(root.join(".git")).unwrap();
+        std::fs::create_dir(root.join("src")).unwrap();
+        std::fs::write(root.join(".gitignore"), "generated.rs\n").unwrap();
+        for name in ["Cargo.toml", "README.md", "src/main.rs", "src/notes.md", "src/generated.rs"] {
+            std::fs::write(root.join(name), "").unwrap();
+        }
+
+        assert_eq!(
+            scanned(Scanner::new(root).exclude("*.md"), root),
+            ["", "Cargo.toml", "src", "src/main.rs"]
+        );
+        // Include globs take precedence over `.gitignore`.
+        assert_eq!(
+            scanned(Scanner::new(root).include("**/*.rs").include("Cargo.toml"), root),
+            ["", "Cargo.toml", "src", "src/generated.rs", "src/main.rs"]
+        );
+        // Exclude globs take precedence over include globs.
+        assert_eq!(
+            scanned(Scanner::new(root).include("*.rs").exclude("generated.rs").exclude("src/"), root),
+            [""]
+        );
+    }
+
+    #[test]
+    fn test_walk_options() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().join("root");
+
+        // Not a git repository.
+        std::fs::create_dir_all(root.join("sub/deeper")).unwrap();
+        std::fs::write(temp_dir.path().join(".ignore"), "from-parent\n").unwrap();
+        std::fs::write(root.join(".gitignore"), "*.log\n").unwrap();
+        for name in [".env", "build.log", "from-parent", "sub/file", "sub/deeper/file"] {
+            std::fs::write(root.join(name), "").unwrap();
+        }
+
+        // `.gitignore` is ignored outside a git repository by default.
+        assert_eq!(
+            scanned(&mut Scanner::new(&root), &root),
+            ["", "build.log", "sub", "sub/deeper", "sub/deeper/file", "sub/file"]
+        );
+        assert_eq!(
+            scanned(Scanner::new(&root).require_git(false), &root),
+            ["", "sub", "sub/deeper", "sub/deeper/file", "sub/file"]
+        );
+        assert_eq!(
+            scanned(Scanner::new(&root).hidden(false).parents(false).max_depth(Some(1)), &root),
+            ["", ".env", ".gitignore", "build.log", "from-parent", "sub"]
+        );
+        assert_eq!(
+            scanned(Scanner::new(&root).require_git(false).git_ignore(false).max_depth(Some(1)), &root),
+            ["", "build.log", "sub"]
+        );
+    }
+
+    #[test]
+    fn test_ignore_files() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().join("root");
+
+        std::fs::create_dir_all(root.join(".git")).unwrap();
+        std::fs::create_dir(root.join("sub")).unwrap();
+        std::fs::write(root.join(".gitignore"), "*.log\n!important.bak\n").unwrap();
+        std::fs::write(root.join(".dockerignore"), "*.tmp\n").unwrap();
+        std::fs::write(root.join("sub/.maxtimeignore"), "!keep.log\n").unwrap();
+        let global = temp_dir.path().join("global-ignore");
+        std::fs::write(&global, "*.bak\n").unwrap();
+        for name in ["a.log", "a.tmp", "a.bak", "important.bak", "sub/keep.log", "sub/other.log", "sub/b.tmp"] {
+            std::fs::write(root.join(name), "").unwrap();
+        }
+
+        assert_eq!(
+            scanned(&mut Scanner::new(&root), &root),
+            ["", "a.bak", "a.tmp", "important.bak", "sub", "sub/b.tmp"]
+        );
+        // Custom ignore files layer on top of `.gitignore` and can whitelist
+        // things it ignores, and `.gitignore` can whitelist things ignored by
+        // an explicit ignore file.
+        assert_eq!(
+            scanned(
+                Scanner::new(&root)
+                    .ignore_file_name(".dockerignore")
+                    .ignore_file_name(".maxtimeignore")
+                    .ignore_file(&global),
+                &root
+            ),
+            ["", "important.bak", "sub", "sub/keep.log"]
+        );
+
+        assert!(Scanner::new(&root).ignore_file(temp_dir.path().join("missing")).scan().is_err());
+    }
+
+    #[test]
+    fn test_invalid_glob() {
+        assert!(Scanner::new(".").include("a[").scan().is_err());
+    }
+
+    #[test]
+    fn test_time_kind() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+
+        let before = SystemTime::now() - std::time::Duration::from_secs(1);
+        let path = root.join("file");
+        std::fs::write(&path, "").unwrap();
+        // Simulate `cp -p` of an old file.
+        filetime::set_file_mtime(&path, filetime::FileTime::from_system_time(UNIX_EPOCH)).unwrap();
+        filetime::set_file_mtime(root, filetime::FileTime::from_system_time(UNIX_EPOCH)).unwrap();
+
+        let result = Scanner::new(root).scan().unwrap().into_result().unwrap();
+        assert_eq!(result.max_time, UNIX_EPOCH);
+
+        if cfg!(unix) {
+            let result = Scanner::new(root).time_kind(TimeKind::Ctime).scan().unwrap().into_result().unwrap();
+            assert!(result.max_time > before);
+        }
+    }
+
+    #[test]
+    fn test_json() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().join("root");
+        std::fs::create_dir(&root).unwrap();
+        let file = root.join("fi\"le");
+        std::fs::write(&file, "").unwrap();
+        filetime::set_file_mtime(&file, filetime::FileTime::from_unix_time(1_000_000_000, 5)).unwrap();
+        filetime::set_file_mtime(&root, filetime::FileTime::from_unix_time(0, 0)).unwrap();
+
+        let result = Scanner::new(&root).top(1).scan().unwrap().into_result().unwrap();
+        let root_json = json::path(&root);
+        let file_json = json::path(&file);
+
+        assert_eq!(
+            result.to_json().unwrap(),
+            format!(
+                concat!(
+                    r#"{{"version":1,"max_time_nanos":1000000000000000005,"max_time":"2001-09-09T01:46:40.000000005Z","#,
+                    r#""max_path":{file},"entries":2,"fingerprint":null,"hash":null,"vanished":0,"cached_dirs":0,"#,
+                    r#""roots":[{{"path":{root},"max_time_nanos":1000000000000000005,"max_time":"2001-09-09T01:46:40.000000005Z","max_path":{file},"entries":2}}],"#,
+                    r#""top":[{{"path":{file},"time_nanos":1000000000000000005,"time":"2001-09-09T01:46:40.000000005Z"}}],"#,
+                    r#""errors":[]}}"#,
+                ),
+                root = root_json,
+                file = file_json,
+            )
+        );
+    }
+
+    #[test]
+    fn test_vanished() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let vanished = || ignore::Error::WithPath {
+            path: temp_dir.path().join("deleted"),
+            err: Box::new(ignore::Error::Io(std::io::ErrorKind::NotFound.into())),
+        };
+
+        let scanner = Scanner::new(temp_dir.path());
+        let totals = Arc::new(Mutex::new(Totals::new(&scanner)));
+        let mut visitor = MtimeVisitor::new(&scanner, &[], None, totals.clone());
+        assert!(matches!(ignore::ParallelVisitor::visit(&mut visitor, Err(vanished())), ignore::WalkState::Continue));
+        drop(visitor);
+        assert_eq!(totals.lock().unwrap().vanished, 1);
+        assert!(totals.lock().unwrap().errors.is_empty());
+
+        let mut scanner = Scanner::new(temp_dir.path());
+        scanner.strict(true);
+        let totals = Arc::new(Mutex::new(Totals::new(&scanner)));
+        let mut visitor = MtimeVisitor::new(&scanner, &[], None, totals.clone());
+        assert!(matches!(ignore::ParallelVisitor::visit(&mut visitor, Err(vanished())), ignore::WalkState::Quit));
+        drop(visitor);
+        assert_eq!(totals.lock().unwrap().vanished, 0);
+        assert_eq!(totals.lock().unwrap().errors.len(), 1);
+    }
+
+    #[test]
+    fn test_keep_going() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let denied = |name: &str| ignore::Error::WithDepth {
+            depth: 1,
+            err: Box::new(ignore::Error::WithPath {
+                path: temp_dir.path().join(name),
+                err: Box::new(ignore::Error::Io(std::io::ErrorKind::PermissionDenied.into())),
+            }),
+        };
+
+        let mut scanner = Scanner::new(temp_dir.path());
+        scanner.keep_going(true);
+        let totals = Arc::new(Mutex::new(Totals::new(&scanner)));
+        let mut visitor = MtimeVisitor::new(&scanner, &[], None, totals.clone());
+        assert!(matches!(ignore::ParallelVisitor::visit(&mut visitor, Err(denied("a"))), ignore::WalkState::Continue));
+        assert!(matches!(ignore::ParallelVisitor::visit(&mut visitor, Err(denied("b"))), ignore::WalkState::Continue));
+        drop(visitor);
+
+        let paths: Vec<_> = totals.lock().unwrap().errors.iter().map(|e| e.path.clone()).collect();
+        assert_eq!(paths, [Some(temp_dir.path().join("a")), Some(temp_dir.path().join("b"))]);
+
+        // The walk still scans everything else.
+        std::fs::write(temp_dir.path().join("file"), "").unwrap();
+        let result = scanner.scan().unwrap();
+        assert!(result.errors.is_empty());
+        assert_eq!(result.entries, 2);
+    }
+
+    #[test]
+    fn test_missing_path() {
+        let temp_dir = tempfile::tempdir().unwrap();
+
+        assert!(Scanner::new(temp_dir.path().join("missing")).scan().is_err());
+    }
+}
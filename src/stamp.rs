@@ -0,0 +1,321 @@
+//! Stamp files record the result of a scan. Their contents are the max mtime
+//! in nanoseconds since the Unix epoch, and their own mtime is set to the max
+//! mtime too, so they work with tools that compare mtimes (e.g. Make) and
+//! tools that compare contents.
+//!
+//! With `--fingerprint` there is a second line, `fingerprint <hex>`, so that
+//! tools that compare contents also see deleted and renamed files.
+//!
+//! With `--hash` the contents are just `hash <hex>`, and the stamp's mtime is
+//! the time the hash last changed, so that new mtimes with the same contents
+//! (e.g. after a fresh clone) don't count as a change.
+
+use std::ffi::OsString;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, Context, Result};
+
+/// The coarsest mtime precision of common filesystems (FAT's is two seconds).
+/// A stamp whose mtime is this close to the right one isn't written again.
+const MTIME_PRECISION: Duration = Duration::from_secs(2);
+
+/// The contents and mtime of a stamp file.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Stamp {
+    pub contents: String,
+    pub mtime: SystemTime,
+}
+
+impl Stamp {
+    /// The stamp for a scan with the given max time.
+    pub fn new(time: SystemTime) -> Self {
+        Self {
+            contents: format!("{}\n", time::OffsetDateTime::from(time).unix_timestamp_nanos()),
+            mtime: time,
+        }
+    }
+
+    /// The stamp for a scan with the given max time and fingerprint.
+    pub fn with_fingerprint(time: SystemTime, fingerprint: &str) -> Self {
+        let mut stamp = Self::new(time);
+        stamp.contents.push_str(&format!("fingerprint {}\n", fingerprint));
+        stamp
+    }
+
+    /// The stamp for a content hash. `now` becomes its mtime if it is written.
+    pub fn with_hash(hash: &str, now: SystemTime) -> Self {
+        Self {
+            contents: format!("hash {}\n", hash),
+            mtime: now,
+        }
+    }
+
+    /// Read a stamp file. Returns `None` if it doesn't exist.
+    pub fn read(path: &Path) -> Result<Option<Self>> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| anyhow!("error reading stamp file {}", path.display())),
+        };
+        let mtime = std::fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .with_context(|| anyhow!("error getting modified time of stamp file {}", path.display()))?;
+        Ok(Some(Self { contents, mtime }))
+    }
+
+    /// Write the stamp file and set its mtime. This is atomic: the stamp is
+    /// written to a temporary file in the same directory which is then renamed
+    /// over the target, so concurrent readers never see a truncated stamp or
+    /// one with the wrong mtime. Missing parent directories are created.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        write_atomic(path, self.contents.as_bytes(), Some(self.mtime), "stamp file")
+    }
+
+    /// Write the stamp file unless it already has the same contents and mtime.
+    /// Leaving it alone avoids waking up file watchers and lets tools like
+    /// Ninja's `restat` see that nothing changed. Returns true if the file
+    /// was written.
+    ///
+    /// The mtimes only have to match to within `MTIME_PRECISION`, since some
+    /// filesystems round them. The contents have the time in full anyway.
+    pub fn update(&self, path: &Path) -> Result<bool> {
+        if Self::read(path)?.is_some_and(|stamp| {
+            let difference = stamp.mtime.duration_since(self.mtime).unwrap_or_else(|e| e.duration());
+            stamp.contents == self.contents && difference < MTIME_PRECISION
+        }) {
+            return Ok(false);
+        }
+        self.write(path)?;
+        Ok(true)
+    }
+
+    /// Write the stamp file unless it already has the same contents, whatever
+    /// its mtime. Used for content hashes, so the mtime only moves forward when
+    /// the hash changes. Returns true if the file was written.
+    pub fn update_contents(&self, path: &Path) -> Result<bool> {
+        if Self::read(path)?.is_some_and(|stamp| stamp.contents == self.contents) {
+            return Ok(false);
+        }
+        self.write(path)?;
+        Ok(true)
+    }
+
+    /// The time recorded in the stamp. This is read from the contents so that
+    /// it has full precision even on filesystems with coarse mtimes, but falls
+    /// back to the stamp's mtime if the contents aren't a time (e.g. it was
+    /// created with `touch`).
+    pub fn time(&self) -> SystemTime {
+        self.contents
+            .lines()
+            .next()
+            .and_then(|line| line.trim().parse::<i128>().ok())
+            .and_then(|nanos| time::OffsetDateTime::from_unix_timestamp_nanos(nanos).ok())
+            .map_or(self.mtime, SystemTime::from)
+    }
+
+    /// The fingerprint recorded in the stamp, if it has one.
+    pub fn fingerprint(&self) -> Option<&str> {
+        self.field("fingerprint")
+    }
+
+    /// The content hash recorded in the stamp, if it has one.
+    pub fn hash(&self) -> Option<&str> {
+        self.field("hash")
+    }
+
+    // The value of a `name value` line.
+    fn field(&self, name: &str) -> Option<&str> {
+        self.contents.lines().find_map(|line| {
+            let (line_name, value) = line.split_once(' ')?;
+            (line_name == name).then(|| value.trim())
+        })
+    }
+}
+
+/// Write a file atomically, optionally setting its mtime: it is written to a
+/// temporary file in the same directory which is then renamed over the target.
+/// Missing parent directories are created. `what` describes the file in error
+/// messages, e.g. "stamp file".
+///
+/// The directory's mtime is put back afterwards, because the file is often in
+/// the scanned tree and the next scan would otherwise see a new max mtime.
+pub(crate) fn write_atomic(path: &Path, contents: &[u8], mtime: Option<SystemTime>, what: &str) -> Result<()> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("{} path {} has no file name", what, path.display()))?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    std::fs::create_dir_all(dir).with_context(|| anyhow!("error creating directory {} for {}", dir.display(), what))?;
+
+    // The process ID keeps concurrent writers apart. Anything already
+    // there was left behind by a dead process so it's fine to truncate it.
+    let mut temp_name = OsString::from(".");
+    temp_name.push(file_name);
+    temp_name.push(format!(".{}.tmp", std::process::id()));
+    let temp_path = dir.join(temp_name);
+    let dir_mtime = std::fs::metadata(dir).and_then(|metadata| metadata.modified());
+
+    let result = write_temp(&temp_path, contents, mtime, what).and_then(|()| {
+        std::fs::rename(&temp_path, path).map_err(|e| {
+            let context = if e.kind() == std::io::ErrorKind::CrossesDevices {
+                format!(
+                    "{} {} is on a different filesystem to its directory (is it a mount point?)",
+                    what,
+                    path.display()
+                )
+            } else {
+                format!("error renaming {} to {} {}", temp_path.display(), what, path.display())
+            };
+            anyhow::Error::new(e).context(context)
+        })
+    });
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    // Best effort, e.g. it fails if the directory belongs to someone else.
+    if let Ok(dir_mtime) = dir_mtime {
+        let _ = filetime::set_file_mtime(dir, filetime::FileTime::from_system_time(dir_mtime));
+    }
+    result
+}
+
+fn write_temp(temp_path: &Path, contents: &[u8], mtime: Option<SystemTime>, what: &str) -> Result<()> {
+    let mut file = std::fs::File::create(temp_path)
+        .with_context(|| anyhow!("error creating temporary {} {}", what, temp_path.display()))?;
+    file.write_all(contents)
+        .with_context(|| anyhow!("error writing temporary {} {}", what, temp_path.display()))?;
+    if let Some(mtime) = mtime {
+        filetime::set_file_handle_times(&file, None, Some(filetime::FileTime::from_system_time(mtime)))
+            .with_context(|| anyhow!("error setting mtime of temporary {} {}", what, temp_path.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_stamp_time() {
+        let mtime = UNIX_EPOCH + Duration::from_secs(10);
+
+        let stamp = Stamp {
+            contents: "1500000000\n".to_owned(),
+            mtime,
+        };
+        assert_eq!(stamp.time(), UNIX_EPOCH + Duration::from_millis(1500));
+
+        let stamp = Stamp {
+            contents: String::new(),
+            mtime,
+        };
+        assert_eq!(stamp.time(), mtime);
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let time = UNIX_EPOCH + Duration::from_secs(10);
+
+        let stamp = Stamp::with_fingerprint(time, "0123abcd");
+        assert_eq!(stamp.contents, "10000000000\nfingerprint 0123abcd\n");
+        assert_eq!(stamp.time(), time);
+        assert_eq!(stamp.fingerprint(), Some("0123abcd"));
+
+        assert_eq!(Stamp::new(time).fingerprint(), None);
+    }
+
+    #[test]
+    fn test_update() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("out.stamp");
+
+        let stamp = Stamp::new(UNIX_EPOCH + Duration::from_secs(10));
+        assert_eq!(stamp.contents, "10000000000\n");
+
+        assert!(stamp.update(&path).unwrap());
+        assert_eq!(Stamp::read(&path).unwrap().as_ref(), Some(&stamp));
+        assert!(!stamp.update(&path).unwrap());
+
+        // Rewritten if just the mtime is wrong.
+        filetime::set_file_mtime(&path, filetime::FileTime::from_system_time(UNIX_EPOCH)).unwrap();
+        assert!(stamp.update(&path).unwrap());
+        assert!(!stamp.update(&path).unwrap());
+
+        // But not if the filesystem rounded it, e.g. to whole seconds.
+        let precise = Stamp::new(UNIX_EPOCH + Duration::new(10, 999_999_999));
+        assert!(precise.update(&path).unwrap());
+        filetime::set_file_mtime(&path, filetime::FileTime::from_unix_time(10, 0)).unwrap();
+        assert!(!precise.update(&path).unwrap());
+
+        // Or just the contents.
+        let newer = Stamp::new(UNIX_EPOCH + Duration::from_secs(20));
+        assert!(newer.update(&path).unwrap());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "20000000000\n");
+    }
+
+    #[test]
+    fn test_hash() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("out.stamp");
+        let first = UNIX_EPOCH + Duration::from_secs(10);
+        let second = UNIX_EPOCH + Duration::from_secs(20);
+
+        let stamp = Stamp::with_hash("0123abcd", first);
+        assert_eq!(stamp.contents, "hash 0123abcd\n");
+        assert_eq!(stamp.hash(), Some("0123abcd"));
+        assert_eq!(stamp.fingerprint(), None);
+        assert!(stamp.update_contents(&path).unwrap());
+
+        // The mtime is left alone while the hash is the same.
+        assert!(!Stamp::with_hash("0123abcd", second).update_contents(&path).unwrap());
+        let stamp = Stamp::read(&path).unwrap().unwrap();
+        assert_eq!(stamp.mtime, first);
+        // There's no time in the contents so it's the mtime.
+        assert_eq!(stamp.time(), first);
+
+        assert!(Stamp::with_hash("4567", second).update_contents(&path).unwrap());
+        assert_eq!(Stamp::read(&path).unwrap().unwrap().mtime, second);
+    }
+
+    #[test]
+    fn test_write_creates_directories() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("a/b/out.stamp");
+
+        let stamp = Stamp::new(UNIX_EPOCH + Duration::from_secs(10));
+        stamp.write(&path).unwrap();
+        assert_eq!(Stamp::read(&path).unwrap().as_ref(), Some(&stamp));
+
+        // The temporary file was renamed away.
+        let names: Vec<_> = std::fs::read_dir(path.parent().unwrap())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["out.stamp"]);
+    }
+
+    #[test]
+    fn test_write_keeps_directory_mtime() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("out.stamp");
+        let dir_mtime = filetime::FileTime::from_unix_time(5, 0);
+        filetime::set_file_mtime(temp_dir.path(), dir_mtime).unwrap();
+
+        Stamp::new(UNIX_EPOCH + Duration::from_secs(10)).write(&path).unwrap();
+        let metadata = std::fs::metadata(temp_dir.path()).unwrap();
+        assert_eq!(filetime::FileTime::from_last_modification_time(&metadata), dir_mtime);
+    }
+
+    #[test]
+    fn test_read_missing() {
+        let temp_dir = tempfile::tempdir().unwrap();
+
+        assert!(Stamp::read(&temp_dir.path().join("missing")).unwrap().is_none());
+    }
+}
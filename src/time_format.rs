@@ -0,0 +1,165 @@
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use anyhow::{anyhow, Context, Result};
+use time::format_description::well_known::{Iso8601, Rfc3339};
+use time::format_description::OwnedFormatItem;
+use time::{OffsetDateTime, UtcOffset};
+
+/// How to print times.
+#[derive(Clone, Debug, Default)]
+pub enum TimeFormat {
+    /// Integer nanoseconds since the Unix epoch.
+    #[default]
+    Nanos,
+    /// Integer milliseconds since the Unix epoch, rounded down.
+    Millis,
+    /// Integer seconds since the Unix epoch, rounded down.
+    Secs,
+    /// RFC 3339, e.g. `2023-05-13T17:46:40.123456789Z`.
+    Rfc3339,
+    /// ISO 8601, e.g. `2023-05-13T17:46:40.123456789Z`.
+    Iso8601,
+    /// A `time` crate format description (version 2), e.g.
+    /// `[year]-[month]-[day] [hour]:[minute]`.
+    Custom(OwnedFormatItem),
+    /// Relative to now, e.g. `5 minutes ago`.
+    Relative,
+}
+
+/// The time zone for formats that show a date and time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TimeZone {
+    #[default]
+    Utc,
+    /// The system's local time zone. Note that on Unix this can only be
+    /// determined while the process is single threaded.
+    Local,
+}
+
+impl TimeFormat {
+    /// Format a time.
+    pub fn format(&self, time: SystemTime, zone: TimeZone) -> Result<String> {
+        self.format_at(time, zone, SystemTime::now())
+    }
+
+    fn format_at(&self, time: SystemTime, zone: TimeZone, now: SystemTime) -> Result<String> {
+        let date_time = OffsetDateTime::from(time);
+        let nanos = date_time.unix_timestamp_nanos();
+        let in_zone = || -> Result<OffsetDateTime> {
+            Ok(match zone {
+                TimeZone::Utc => date_time,
+                TimeZone::Local => date_time.to_offset(
+                    UtcOffset::local_offset_at(date_time).context("error getting the local time zone")?,
+                ),
+            })
+        };
+        Ok(match self {
+            Self::Nanos => nanos.to_string(),
+            Self::Millis => nanos.div_euclid(1_000_000).to_string(),
+            Self::Secs => nanos.div_euclid(1_000_000_000).to_string(),
+            Self::Rfc3339 => in_zone()?.format(&Rfc3339).context("error formatting time as RFC 3339")?,
+            Self::Iso8601 => in_zone()?.format(&Iso8601::DEFAULT).context("error formatting time as ISO 8601")?,
+            Self::Custom(format) => in_zone()?.format(format).context("error formatting time")?,
+            Self::Relative => relative(time, now),
+        })
+    }
+}
+
+impl FromStr for TimeFormat {
+    type Err = anyhow::Error;
+
+    /// Parse one of `nanos`, `millis`, `secs`, `rfc3339`, `iso8601`,
+    /// `relative`, or else a custom format description.
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "nanos" => Self::Nanos,
+            "millis" => Self::Millis,
+            "secs" => Self::Secs,
+            "rfc3339" => Self::Rfc3339,
+            "iso8601" => Self::Iso8601,
+            "relative" => Self::Relative,
+            _ => Self::Custom(
+                time::format_description::parse_owned::<2>(s)
+                    .with_context(|| anyhow!("invalid time format description {:?}", s))?,
+            ),
+        })
+    }
+}
+
+/// A human readable description of how long before (or after) `now` a time is.
+fn relative(time: SystemTime, now: SystemTime) -> String {
+    let (secs, future) = match now.duration_since(time) {
+        Ok(ago) => (ago.as_secs(), false),
+        Err(e) => (e.duration().as_secs(), true),
+    };
+    if secs == 0 {
+        return "now".to_owned();
+    }
+    const UNITS: [(&str, u64); 5] = [
+        ("year", 365 * 24 * 60 * 60),
+        ("day", 24 * 60 * 60),
+        ("hour", 60 * 60),
+        ("minute", 60),
+        ("second", 1),
+    ];
+    let (unit, unit_secs) = UNITS
+        .into_iter()
+        .find(|(_, unit_secs)| secs >= *unit_secs)
+        .unwrap_or(UNITS[UNITS.len() - 1]);
+    let count = secs / unit_secs;
+    let plural = if count == 1 { "" } else { "s" };
+    if future {
+        format!("in {} {}{}", count, unit, plural)
+    } else {
+        format!("{} {}{} ago", count, unit, plural)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_formats() {
+        let time = UNIX_EPOCH + Duration::new(1_000_000_000, 123_456_789);
+        let format = |format: &str| {
+            format
+                .parse::<TimeFormat>()
+                .unwrap()
+                .format_at(time, TimeZone::Utc, time + Duration::from_secs(300))
+                .unwrap()
+        };
+
+        assert_eq!(format("nanos"), "1000000000123456789");
+        assert_eq!(format("millis"), "1000000000123");
+        assert_eq!(format("secs"), "1000000000");
+        assert_eq!(format("rfc3339"), "2001-09-09T01:46:40.123456789Z");
+        assert_eq!(format("iso8601"), "2001-09-09T01:46:40.123456789Z");
+        assert_eq!(format("[year]-[month]-[day] [hour]:[minute]"), "2001-09-09 01:46");
+        assert_eq!(format("relative"), "5 minutes ago");
+
+        assert!("[nonsense]".parse::<TimeFormat>().is_err());
+    }
+
+    #[test]
+    fn test_negative() {
+        let time = UNIX_EPOCH - Duration::from_millis(1500);
+
+        assert_eq!(TimeFormat::Secs.format(time, TimeZone::Utc).unwrap(), "-2");
+        assert_eq!(TimeFormat::Millis.format(time, TimeZone::Utc).unwrap(), "-1500");
+    }
+
+    #[test]
+    fn test_relative() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+
+        assert_eq!(relative(now, now), "now");
+        assert_eq!(relative(now - Duration::from_secs(1), now), "1 second ago");
+        assert_eq!(relative(now - Duration::from_secs(7200), now), "2 hours ago");
+        assert_eq!(relative(now - Duration::from_secs(400 * 86400), now), "1 year ago");
+        assert_eq!(relative(now + Duration::from_secs(86400), now), "in 1 day");
+    }
+}
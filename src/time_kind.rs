@@ -0,0 +1,109 @@
+use std::fmt;
+use std::fs::Metadata;
+use std::io;
+use std::time::SystemTime;
+
+/// Which of an entry's timestamps to use.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TimeKind {
+    /// Last modification time.
+    #[default]
+    Mtime,
+    /// Last status change time. Unlike the mtime this can't be set to an old
+    /// value, so it catches files copied with `cp -p`, extracted with `tar` or
+    /// synced with `rsync -t`. Only available on Unix.
+    Ctime,
+    /// Last access time. Many filesystems are mounted with `noatime` or
+    /// `relatime` so this may not be updated on every access.
+    Atime,
+    /// Birth (creation) time. Not all platforms and filesystems record this.
+    Btime,
+    /// Time of the last commit that changed the file, which is the same in
+    /// every clone. Files that are untracked or have uncommitted changes use
+    /// their mtime instead, and directories don't count because git doesn't
+    /// track them. The scanned paths must be in git repositories.
+    GitCommit,
+}
+
+impl TimeKind {
+    /// Get this timestamp from an entry's metadata. For `GitCommit` this is
+    /// the mtime, which is used when there is no commit time.
+    pub fn get(self, metadata: &Metadata) -> io::Result<SystemTime> {
+        match self {
+            Self::Mtime | Self::GitCommit => metadata.modified(),
+            Self::Ctime => ctime(metadata),
+            Self::Atime => metadata.accessed(),
+            Self::Btime => metadata.created(),
+        }
+    }
+}
+
+impl fmt::Display for TimeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Mtime => "mtime",
+            Self::Ctime => "ctime",
+            Self::Atime => "atime",
+            Self::Btime => "btime",
+            Self::GitCommit => "git commit time",
+        })
+    }
+}
+
+#[cfg(unix)]
+fn ctime(metadata: &Metadata) -> io::Result<SystemTime> {
+    use std::os::unix::fs::MetadataExt;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let secs = metadata.ctime();
+    let nanos = Duration::from_nanos(metadata.ctime_nsec() as u64);
+    Ok(if secs >= 0 {
+        UNIX_EPOCH + Duration::from_secs(secs as u64) + nanos
+    } else {
+        UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs()) + nanos
+    })
+}
+
+#[cfg(not(unix))]
+fn ctime(_metadata: &Metadata) -> io::Result<SystemTime> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "ctime is not available on this platform",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_time_kinds() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("file");
+
+        let before = SystemTime::now() - Duration::from_secs(1);
+        std::fs::write(&path, "").unwrap();
+        let mtime = UNIX_EPOCH + Duration::from_secs(10);
+        let atime = UNIX_EPOCH + Duration::from_secs(20);
+        filetime::set_file_times(
+            &path,
+            filetime::FileTime::from_system_time(atime),
+            filetime::FileTime::from_system_time(mtime),
+        )
+        .unwrap();
+
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert_eq!(TimeKind::Mtime.get(&metadata).unwrap(), mtime);
+        assert_eq!(TimeKind::Atime.get(&metadata).unwrap(), atime);
+        if cfg!(unix) {
+            // Setting the times changes the ctime to now.
+            assert!(TimeKind::Ctime.get(&metadata).unwrap() > before);
+        }
+        // Not all filesystems have a birth time, but it can't be faked.
+        if let Ok(btime) = TimeKind::Btime.get(&metadata) {
+            assert!(btime > before);
+        }
+    }
+}
@@ -0,0 +1,304 @@
+//! Rescan whenever something in the tree changes, using Linux's inotify.
+//!
+//! Every directory found by a scan is watched, so directories that are
+//! ignored (e.g. `target/`) are not, and new directories are picked up by the
+//! scan that their creation triggers. Events in a watched directory always
+//! trigger a rescan, even for ignored files, but the rescan gives the same
+//! result so nothing is reported.
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::File;
+use std::io::Read;
+use std::ops::ControlFlow;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::{ScanResult, Scanner};
+
+const MASK: u32 = libc::IN_ATTRIB
+    | libc::IN_CLOSE_WRITE
+    | libc::IN_CREATE
+    | libc::IN_DELETE
+    | libc::IN_DELETE_SELF
+    | libc::IN_MODIFY
+    | libc::IN_MOVE_SELF
+    | libc::IN_MOVED_FROM
+    | libc::IN_MOVED_TO;
+
+/// A set of inotify watches.
+pub struct Watcher {
+    inotify: File,
+    // Watched paths by watch descriptor, and the other way round.
+    paths: HashMap<i32, PathBuf>,
+    watches: HashMap<PathBuf, i32>,
+}
+
+impl Watcher {
+    pub fn new() -> Result<Self> {
+        // SAFETY: no pointers are involved, and the descriptor is checked.
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error()).context("error initialising inotify");
+        }
+        Ok(Self {
+            // SAFETY: the descriptor is valid and nothing else owns it.
+            inotify: unsafe { File::from_raw_fd(fd) },
+            paths: HashMap::new(),
+            watches: HashMap::new(),
+        })
+    }
+
+    /// Watch a directory for changes to its entries, or a file for changes to
+    /// its contents. Paths that are already watched, or that no longer exist,
+    /// are skipped.
+    pub fn add(&mut self, path: &Path) -> Result<()> {
+        if self.watches.contains_key(path) {
+            return Ok(());
+        }
+        let c_path = CString::new(path.as_os_str().as_bytes())
+            .with_context(|| anyhow!("path {} contains a NUL byte", path.display()))?;
+        // SAFETY: `c_path` is a valid NUL terminated string.
+        let wd = unsafe { libc::inotify_add_watch(self.inotify.as_raw_fd(), c_path.as_ptr(), MASK) };
+        if wd < 0 {
+            let error = std::io::Error::last_os_error();
+            if error.kind() == std::io::ErrorKind::NotFound {
+                return Ok(());
+            }
+            let context = if error.raw_os_error() == Some(libc::ENOSPC) {
+                format!(
+                    "error watching {}: too many watches (see /proc/sys/fs/inotify/max_user_watches)",
+                    path.display()
+                )
+            } else {
+                format!("error watching {}", path.display())
+            };
+            return Err(anyhow::Error::new(error).context(context));
+        }
+        // inotify watches inodes, so a path that now refers to something
+        // already watched (e.g. a moved directory) gets its descriptor back.
+        if let Some(old_path) = self.paths.insert(wd, path.to_owned()) {
+            self.watches.remove(&old_path);
+        }
+        self.watches.insert(path.to_owned(), wd);
+        Ok(())
+    }
+
+    /// Number of paths being watched.
+    pub fn len(&self) -> usize {
+        self.watches.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.watches.is_empty()
+    }
+
+    /// Block until something changes, then keep reading events until there
+    /// have been none for `debounce`, so that a burst of changes (e.g. an
+    /// editor saving, or a build) only causes one rescan.
+    pub fn wait(&mut self, debounce: Duration) -> Result<()> {
+        self.poll(None)?;
+        self.read_events()?;
+        while self.poll(Some(debounce))? {
+            self.read_events()?;
+        }
+        Ok(())
+    }
+
+    // Wait for events, returning false if there were none before the timeout.
+    fn poll(&self, timeout: Option<Duration>) -> Result<bool> {
+        let timeout_ms = timeout.map_or(-1, |timeout| timeout.as_millis().try_into().unwrap_or(i32::MAX));
+        let mut poll_fd = libc::pollfd {
+            fd: self.inotify.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        loop {
+            // SAFETY: `poll_fd` is valid for the duration of the call.
+            let ready = unsafe { libc::poll(&mut poll_fd, 1, timeout_ms) };
+            if ready >= 0 {
+                return Ok(ready > 0);
+            }
+            let error = std::io::Error::last_os_error();
+            if error.kind() != std::io::ErrorKind::Interrupted {
+                return Err(error).context("error waiting for inotify events");
+            }
+        }
+    }
+
+    // Read all the pending events. Their details don't matter because
+    // everything is rescanned, except that watches for moved or deleted paths
+    // are removed so they can be added again if the path is recreated.
+    fn read_events(&mut self) -> Result<()> {
+        let mut buffer = [0u8; 64 * 1024];
+        loop {
+            let len = match self.inotify.read(&mut buffer) {
+                Ok(len) => len,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e).context("error reading inotify events"),
+            };
+            let mut offset = 0;
+            while offset + std::mem::size_of::<libc::inotify_event>() <= len {
+                // SAFETY: the kernel only writes whole events, and
+                // `read_unaligned` doesn't need the buffer to be aligned.
+                let event = unsafe { std::ptr::read_unaligned(buffer[offset..].as_ptr().cast::<libc::inotify_event>()) };
+                if event.mask & (libc::IN_MOVE_SELF | libc::IN_DELETE_SELF) != 0 {
+                    // A moved directory would otherwise stay watched under its
+                    // old path, and the rescan will watch it at its new one.
+                    // SAFETY: no pointers are involved, and failure (e.g. if the
+                    // watch is already gone) is harmless.
+                    unsafe { libc::inotify_rm_watch(self.inotify.as_raw_fd(), event.wd) };
+                }
+                if event.mask & (libc::IN_IGNORED | libc::IN_MOVE_SELF | libc::IN_DELETE_SELF) != 0 {
+                    if let Some(path) = self.paths.remove(&event.wd) {
+                        self.watches.remove(&path);
+                    }
+                }
+                offset += std::mem::size_of::<libc::inotify_event>() + event.len as usize;
+            }
+        }
+    }
+}
+
+/// Scan, then rescan every time something changes, calling `on_scan` with
+/// each result until it returns `ControlFlow::Break`. Changes are debounced
+/// as described in `Watcher::wait()`.
+pub fn watch(
+    mut scanner: Scanner,
+    debounce: Duration,
+    mut on_scan: impl FnMut(ScanResult) -> Result<ControlFlow<()>>,
+) -> Result<()> {
+    scanner.collect_dirs(true);
+    let mut watcher = Watcher::new()?;
+    loop {
+        // Things created in new directories before they were watched are
+        // missed, so rescan until there are no new directories.
+        let result = loop {
+            let watched = watcher.len();
+            let result = scanner.scan()?;
+            // Watch the scanned paths themselves in case they are files. A
+            // file replaced by renaming another over it (e.g. by an editor)
+            // is a new file that needs a new watch.
+            for path in scanner.paths() {
+                watcher.add(path)?;
+            }
+            for dir in &result.dirs {
+                watcher.add(dir)?;
+            }
+            if watcher.len() == watched {
+                break result;
+            }
+        };
+        if on_scan(result)?.is_break() {
+            return Ok(());
+        }
+        watcher.wait(debounce)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::UNIX_EPOCH;
+
+    use crate::EntryFilter;
+
+    #[test]
+    fn test_watch() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().join("root");
+        std::fs::create_dir(&root).unwrap();
+        std::fs::write(root.join(".gitignore"), "ignored/\n").unwrap();
+        std::fs::create_dir(root.join(".git")).unwrap();
+        std::fs::create_dir(root.join("ignored")).unwrap();
+
+        // Only files count, so that a change to a directory's mtime (which is
+        // reported to the watch on its parent) doesn't have to be undone.
+        let mut scanner = Scanner::new(&root);
+        scanner.entry_filter(EntryFilter::Files);
+        let mut scans = Vec::new();
+        watch(scanner, Duration::from_millis(10), |result| {
+            scans.push(result.max_time.duration_since(UNIX_EPOCH).unwrap().as_secs());
+            // Each change triggers a rescan.
+            match scans.len() {
+                1 => {
+                    // A new directory is watched, and so is what's created in it.
+                    std::fs::create_dir(root.join("dir")).unwrap();
+                    std::fs::write(root.join("dir/file"), "").unwrap();
+                    filetime::set_file_mtime(root.join("dir/file"), filetime::FileTime::from_unix_time(20, 0)).unwrap();
+                }
+                2 => {
+                    filetime::set_file_mtime(root.join("dir/file"), filetime::FileTime::from_unix_time(30, 0)).unwrap();
+                }
+                3 => {
+                    // A directory recreated where one was moved from is watched.
+                    std::fs::rename(root.join("dir"), root.join("moved")).unwrap();
+                    std::fs::create_dir(root.join("dir")).unwrap();
+                }
+                4 => {
+                    std::fs::write(root.join("dir/file"), "").unwrap();
+                    filetime::set_file_mtime(root.join("dir/file"), filetime::FileTime::from_unix_time(40, 0)).unwrap();
+                }
+                _ => return Ok(ControlFlow::Break(())),
+            }
+            Ok(ControlFlow::Continue(()))
+        })
+        .unwrap();
+
+        assert_eq!(scans, [0, 20, 30, 30, 40]);
+    }
+
+    #[test]
+    fn test_watch_file() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let file = temp_dir.path().join("config");
+        let temp_file = temp_dir.path().join("config.tmp");
+        std::fs::write(&file, "").unwrap();
+        filetime::set_file_mtime(&file, filetime::FileTime::from_unix_time(10, 0)).unwrap();
+
+        let mut scans = Vec::new();
+        watch(Scanner::new(&file), Duration::from_millis(10), |result| {
+            scans.push(result.max_time.duration_since(UNIX_EPOCH).unwrap().as_secs());
+            if scans.len() == 3 {
+                return Ok(ControlFlow::Break(()));
+            }
+            // Saved the way editors do, more than once.
+            std::fs::write(&temp_file, "").unwrap();
+            filetime::set_file_mtime(&temp_file, filetime::FileTime::from_unix_time(10 * (scans.len() as i64 + 1), 0)).unwrap();
+            std::fs::rename(&temp_file, &file).unwrap();
+            Ok(ControlFlow::Continue(()))
+        })
+        .unwrap();
+
+        assert_eq!(scans, [10, 20, 30]);
+    }
+
+    #[test]
+    fn test_ignored_dirs_not_watched() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+        std::fs::create_dir(root.join(".git")).unwrap();
+        std::fs::create_dir(root.join("src")).unwrap();
+        std::fs::create_dir(root.join("target")).unwrap();
+        std::fs::write(root.join(".gitignore"), "target/\n").unwrap();
+
+        let mut watcher = Watcher::new().unwrap();
+        let result = Scanner::new(root).collect_dirs(true).scan().unwrap();
+        for dir in &result.dirs {
+            watcher.add(dir).unwrap();
+        }
+        assert_eq!(result.dirs, [root.to_owned(), root.join("src")]);
+        assert_eq!(watcher.len(), 2);
+
+        // Deleted directories stop being watched.
+        std::fs::remove_dir(root.join("src")).unwrap();
+        watcher.wait(Duration::from_millis(10)).unwrap();
+        assert_eq!(watcher.len(), 1);
+    }
+}